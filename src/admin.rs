@@ -0,0 +1,73 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+
+use crate::{
+    auth::AdminKey,
+    llm_delegate::LlmDelegate,
+    usage_ledger::{UsageLedger, UsageQuery},
+};
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum UsageFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct UsageExport {
+    #[serde(default)]
+    format: UsageFormat,
+}
+
+pub async fn usage(
+    _: AdminKey,
+    State(usage_ledger): State<UsageLedger>,
+    Query(export): Query<UsageExport>,
+    Query(query): Query<UsageQuery>,
+) -> Response {
+    let rows = match usage_ledger.query(query.clone()).await {
+        Ok(rows) => rows,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    match export.format {
+        UsageFormat::Json => Json(rows).into_response(),
+        UsageFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"usage.csv\"",
+                ),
+            ],
+            query.csv(&rows),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn circuits(_: AdminKey, State(llm_delegate): State<LlmDelegate>) -> Response {
+    Json(llm_delegate.circuits()).into_response()
+}
+
+pub async fn pools(_: AdminKey, State(llm_delegate): State<LlmDelegate>) -> Response {
+    Json(llm_delegate.pools().await).into_response()
+}
+
+pub async fn metrics(_: AdminKey, State(llm_delegate): State<LlmDelegate>) -> Response {
+    match llm_delegate.metrics() {
+        Ok(metrics) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
@@ -1,25 +1,42 @@
 use axum::extract::FromRef;
 
-use crate::llm_delegate::LlmDelegate;
+use crate::{
+    auth::{ApiKeys, AuthLockout},
+    llm_delegate::LlmDelegate,
+    resumable_streams::ResumableStreams,
+    usage_ledger::UsageLedger,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     llm_delegate: LlmDelegate,
-    token: String,
+    usage_ledger: UsageLedger,
+    api_keys: ApiKeys,
+    auth_lockout: AuthLockout,
+    resumable_streams: Option<ResumableStreams>,
 }
 
 impl AppState {
-    pub fn new(llm_delegate: LlmDelegate, token: String) -> Self {
+    pub fn new(
+        llm_delegate: LlmDelegate,
+        usage_ledger: UsageLedger,
+        api_keys: ApiKeys,
+        auth_lockout: AuthLockout,
+        resumable_streams: Option<ResumableStreams>,
+    ) -> Self {
         Self {
             llm_delegate,
-            token: token.to_string(),
+            usage_ledger,
+            api_keys,
+            auth_lockout,
+            resumable_streams,
         }
     }
 }
 
-impl FromRef<AppState> for String {
-    fn from_ref(app_state: &AppState) -> String {
-        app_state.token.clone()
+impl FromRef<AppState> for ApiKeys {
+    fn from_ref(app_state: &AppState) -> ApiKeys {
+        app_state.api_keys.clone()
     }
 }
 
@@ -28,3 +45,21 @@ impl FromRef<AppState> for LlmDelegate {
         app_state.llm_delegate.clone()
     }
 }
+
+impl FromRef<AppState> for UsageLedger {
+    fn from_ref(app_state: &AppState) -> UsageLedger {
+        app_state.usage_ledger.clone()
+    }
+}
+
+impl FromRef<AppState> for AuthLockout {
+    fn from_ref(app_state: &AppState) -> AuthLockout {
+        app_state.auth_lockout.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<ResumableStreams> {
+    fn from_ref(app_state: &AppState) -> Option<ResumableStreams> {
+        app_state.resumable_streams.clone()
+    }
+}
@@ -0,0 +1,280 @@
+mod jsonl_sink;
+mod redaction;
+mod sqlite_sink;
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    caller::Caller,
+    entities::{CompletionResponseStream, CreateCompletionRequest, StreamAggregator},
+    llm_delegate::ModelTarget,
+    metrics::Metrics,
+    usage_ledger::UsageRecord,
+};
+
+use jsonl_sink::JsonlSink;
+pub use redaction::Redaction;
+use sqlite_sink::SqliteSink;
+
+/// How often records older than the retention period are deleted.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How many records may wait to be written. Further records are dropped, rather than holding
+/// on to the requests and responses of every call while the sink is slow.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Where audit records are written.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditSinkConfig {
+    /// JSON Lines files in `directory`, starting a new file once the current one reaches
+    /// `max_file_bytes`.
+    Jsonl {
+        directory: PathBuf,
+        #[serde(default = "default_max_file_bytes")]
+        max_file_bytes: u64,
+    },
+    Sqlite {
+        path: PathBuf,
+    },
+}
+
+fn default_max_file_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+#[derive(Deserialize)]
+pub struct AuditConfig {
+    pub sink: AuditSinkConfig,
+    /// The share of requests recorded, from 0 to 1.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// How long records are kept. They are kept forever when omitted.
+    #[serde(default)]
+    pub retention_days: Option<u64>,
+    /// Fields replaced with `[REDACTED]` before records are written, as dot-separated paths
+    /// into the record where `*` matches any array element or object member, e.g.
+    /// `request.messages.*.content`.
+    #[serde(default)]
+    pub redact: Vec<String>,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+impl AuditConfig {
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention_days
+            .map(|days| Duration::from_secs(days * 24 * 3600))
+    }
+}
+
+/// A request to the gateway along with the response it got, as retained for compliance.
+#[derive(Default, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch at which the request was received.
+    pub timestamp: u64,
+    pub key: String,
+    /// Whether the caller supplied its own provider API key.
+    pub byok: bool,
+    /// The target that produced the response, unless every target failed.
+    pub target: Option<ModelTarget>,
+    pub stream: bool,
+    pub request: serde_json::Value,
+    /// The response, reassembled from its chunks when streamed.
+    pub response: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+trait AuditSink: Send {
+    fn write(&mut self, record: &serde_json::Value) -> anyhow::Result<()>;
+
+    /// Deletes the records written before `cutoff`, in milliseconds since the Unix epoch.
+    fn prune(&mut self, cutoff: u64) -> anyhow::Result<()>;
+}
+
+/// Audit trail of the requests served by the gateway and their responses.
+///
+/// Records are redacted as configured, then written by a background task, which also deletes
+/// those past the retention period. Records which do not fit in its queue are dropped and
+/// counted in the metrics.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: mpsc::Sender<AuditRecord>,
+    sample_rate: f64,
+    metrics: Metrics,
+}
+
+impl AuditLog {
+    pub fn open(config: AuditConfig, metrics: Metrics) -> anyhow::Result<Self> {
+        let retention = config.retention();
+        let sink: Box<dyn AuditSink> = match config.sink {
+            AuditSinkConfig::Jsonl {
+                directory,
+                max_file_bytes,
+            } => Box::new(JsonlSink::open(directory, max_file_bytes)?),
+            AuditSinkConfig::Sqlite { path } => Box::new(SqliteSink::open(path)?),
+        };
+        let sink = Arc::new(Mutex::new(sink));
+        let redaction = Arc::new(Redaction::new(&config.redact));
+
+        let (sender, mut receiver) = mpsc::channel::<AuditRecord>(QUEUE_CAPACITY);
+
+        tokio::spawn(async move {
+            let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                tokio::select! {
+                    record = receiver.recv() => {
+                        let Some(record) = record else {
+                            break;
+                        };
+
+                        let sink = sink.clone();
+                        let redaction = redaction.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            let mut record = serde_json::to_value(record)?;
+                            redaction.apply(&mut record);
+                            sink.lock().unwrap().write(&record)
+                        })
+                        .await;
+
+                        match result {
+                            Ok(Err(e)) => tracing::error!("failed to write audit record: {e}"),
+                            Err(e) => tracing::error!("audit writer task failed: {e}"),
+                            Ok(Ok(())) => {}
+                        }
+                    }
+                    _ = prune.tick(), if retention.is_some() => {
+                        let cutoff = UsageRecord::now()
+                            .saturating_sub(retention.unwrap_or_default().as_millis() as u64);
+                        let sink = sink.clone();
+                        let result =
+                            tokio::task::spawn_blocking(move || sink.lock().unwrap().prune(cutoff))
+                                .await;
+
+                        match result {
+                            Ok(Err(e)) => tracing::error!("failed to prune audit records: {e}"),
+                            Err(e) => tracing::error!("audit pruning task failed: {e}"),
+                            Ok(Ok(())) => {}
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            sender,
+            sample_rate: config.sample_rate,
+            metrics,
+        })
+    }
+
+    /// Starts auditing a request, unless the caller's key is exempt or the request is left out
+    /// by sampling. The record is written when the returned value is dropped.
+    pub fn start(
+        &self,
+        caller: &Caller,
+        request: &CreateCompletionRequest,
+    ) -> Option<PendingAudit> {
+        if caller.config.skip_audit || rand::random::<f64>() >= self.sample_rate {
+            return None;
+        }
+
+        Some(PendingAudit {
+            audit_log: self.clone(),
+            started: Instant::now(),
+            record: AuditRecord {
+                timestamp: UsageRecord::now(),
+                key: caller.key.clone(),
+                byok: !caller.provider_api_keys.is_empty(),
+                target: None,
+                stream: request.stream.unwrap_or_default(),
+                request: serde_json::to_value(request).unwrap_or_default(),
+                response: None,
+                error: None,
+                latency_ms: 0,
+            },
+        })
+    }
+
+    fn record(&self, record: AuditRecord) {
+        match self.sender.try_send(record) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.metrics.count_dropped_audit_record();
+                tracing::warn!("audit log queue is full, dropping record");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::error!("audit log writer has stopped, dropping record");
+            }
+        }
+    }
+}
+
+/// A request being audited, recorded once dropped with the outcome set on it by then.
+pub struct PendingAudit {
+    audit_log: AuditLog,
+    started: Instant,
+    record: AuditRecord,
+}
+
+impl PendingAudit {
+    pub fn succeed(&mut self, target: &ModelTarget, response: serde_json::Value) {
+        self.record.target = Some(target.clone());
+        self.record.response = Some(response);
+    }
+
+    pub fn fail(&mut self, error: &anyhow::Error) {
+        self.record.error = Some(error.to_string());
+    }
+
+    /// Passes a stream through, recording the response reassembled from its chunks once it
+    /// ends.
+    pub fn stream(
+        mut self,
+        target: &ModelTarget,
+        mut stream: CompletionResponseStream,
+    ) -> CompletionResponseStream {
+        // Known before the stream ends, in case it is cancelled.
+        self.record.target = Some(target.clone());
+        let target = target.clone();
+
+        Box::pin(async_stream::stream! {
+            let mut aggregator = StreamAggregator::new();
+            while let Some(item) = stream.next().await {
+                match &item {
+                    Ok(chunk) => aggregator.push(chunk),
+                    Err(e) => self.fail(e),
+                }
+
+                yield item;
+            }
+
+            // A failed stream is recorded with its error only.
+            if self.record.error.is_none() {
+                self.succeed(&target, serde_json::to_value(aggregator.finish()).unwrap_or_default());
+            }
+        })
+    }
+}
+
+impl Drop for PendingAudit {
+    fn drop(&mut self) {
+        self.record.latency_ms = self.started.elapsed().as_millis() as u64;
+        if self.record.response.is_none() && self.record.error.is_none() {
+            self.record.error = Some("cancelled before completing".to_string());
+        }
+
+        self.audit_log.record(std::mem::take(&mut self.record));
+    }
+}
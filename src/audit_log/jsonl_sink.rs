@@ -0,0 +1,79 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::usage_ledger::UsageRecord;
+
+use super::AuditSink;
+
+/// Writes audit records as JSON Lines, to `audit-<timestamp>.jsonl` files rotated by size.
+pub struct JsonlSink {
+    directory: PathBuf,
+    max_file_bytes: u64,
+    path: PathBuf,
+    file: File,
+    written: u64,
+}
+
+impl JsonlSink {
+    pub fn open(directory: PathBuf, max_file_bytes: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(&directory)?;
+        let (path, file) = new_file(&directory)?;
+
+        Ok(Self {
+            directory,
+            max_file_bytes,
+            path,
+            file,
+            written: 0,
+        })
+    }
+}
+
+impl AuditSink for JsonlSink {
+    fn write(&mut self, record: &serde_json::Value) -> anyhow::Result<()> {
+        if self.written >= self.max_file_bytes {
+            (self.path, self.file) = new_file(&self.directory)?;
+            self.written = 0;
+        }
+
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn prune(&mut self, cutoff: u64) -> anyhow::Result<()> {
+        let cutoff = UNIX_EPOCH + Duration::from_millis(cutoff);
+
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            let is_audit_file = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("audit-") && name.ends_with(".jsonl"));
+            if !is_audit_file || path == self.path {
+                continue;
+            }
+
+            // Files are only appended to, so their last write is their newest record.
+            if fs::metadata(&path)?.modified()? < cutoff {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn new_file(directory: &Path) -> anyhow::Result<(PathBuf, File)> {
+    let path = directory.join(format!("audit-{}.jsonl", UsageRecord::now()));
+    let file = File::options().create(true).append(true).open(&path)?;
+
+    Ok((path, file))
+}
@@ -0,0 +1,60 @@
+use serde_json::Value;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// The fields blanked out of audit records.
+pub struct Redaction {
+    paths: Vec<Vec<String>>,
+}
+
+impl Redaction {
+    pub fn new(paths: &[String]) -> Self {
+        Self {
+            paths: paths
+                .iter()
+                .map(|path| path.split('.').map(str::to_string).collect())
+                .collect(),
+        }
+    }
+
+    pub fn apply(&self, record: &mut Value) {
+        for path in &self.paths {
+            redact(record, path);
+        }
+    }
+}
+
+fn redact(value: &mut Value, path: &[String]) {
+    let Some((segment, rest)) = path.split_first() else {
+        if !value.is_null() {
+            *value = Value::String(REDACTED.to_string());
+        }
+        return;
+    };
+
+    match value {
+        Value::Object(members) if segment == "*" => {
+            members.values_mut().for_each(|member| redact(member, rest));
+        }
+        Value::Object(members) => {
+            if let Some(member) = members.get_mut(segment) {
+                redact(member, rest);
+            }
+        }
+        Value::Array(elements) if segment == "*" => {
+            elements
+                .iter_mut()
+                .for_each(|element| redact(element, rest));
+        }
+        Value::Array(elements) => {
+            if let Some(element) = segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| elements.get_mut(index))
+            {
+                redact(element, rest);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use super::AuditSink;
+
+/// Writes audit records to the `audit` table of a SQLite database.
+pub struct SqliteSink {
+    connection: Connection,
+}
+
+impl SqliteSink {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                key TEXT NOT NULL,
+                byok INTEGER NOT NULL,
+                target TEXT,
+                stream INTEGER NOT NULL,
+                request TEXT NOT NULL,
+                response TEXT,
+                error TEXT,
+                latency_ms INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS audit_timestamp ON audit (timestamp);",
+        )?;
+
+        Ok(Self { connection })
+    }
+}
+
+impl AuditSink for SqliteSink {
+    fn write(&mut self, record: &Value) -> anyhow::Result<()> {
+        let json = |field: &str| {
+            Some(&record[field])
+                .filter(|v| !v.is_null())
+                .map(Value::to_string)
+        };
+
+        self.connection.execute(
+            "INSERT INTO audit (
+                timestamp, key, byok, target, stream, request, response, error, latency_ms
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                record["timestamp"].as_i64(),
+                record["key"].as_str(),
+                record["byok"].as_bool(),
+                json("target"),
+                record["stream"].as_bool(),
+                record["request"].to_string(),
+                json("response"),
+                record["error"].as_str(),
+                record["latency_ms"].as_i64(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn prune(&mut self, cutoff: u64) -> anyhow::Result<()> {
+        self.connection.execute(
+            "DELETE FROM audit WHERE timestamp < ?1",
+            params![cutoff as i64],
+        )?;
+
+        Ok(())
+    }
+}
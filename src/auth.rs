@@ -1,23 +1,374 @@
-use std::convert::Infallible;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::{Request, State},
-    http::StatusCode,
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::request::Parts,
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
 use axum_extra::TypedHeader;
-use headers::{authorization::Bearer, Authorization};
+use headers::{authorization::Bearer, Authorization, Header};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    caller::{Caller, ProviderApiKeys},
+    config::KeyConfig,
+    error::ApiError,
+    llm_delegate::{Priority, SupportedLlm},
+    telemetry::REQUEST_ID,
+    tls::ClientCertificate,
+};
+
+/// Provider API keys are passed as `x-provider-api-key-<provider>`, such as
+/// `x-provider-api-key-openai`, so that none is ever sent to another provider.
+const PROVIDER_API_KEY_PREFIX: &str = "x-provider-api-key-";
+/// A provider API key for the provider selected by `x-llm-provider`, the request then being
+/// kept to that provider.
+static PROVIDER_API_KEY: HeaderName = HeaderName::from_static("x-provider-api-key");
+static PRIORITY: HeaderName = HeaderName::from_static("x-llm-priority");
+const WEBSOCKET_BEARER: &str = "bearer.";
+
+/// The gateway keys accepted by the server.
+#[derive(Clone)]
+pub struct ApiKeys(Arc<Vec<(String, Caller)>>);
+
+impl ApiKeys {
+    /// Builds the key set from the configured keys, plus the unrestricted, admin, `default` key
+    /// authenticated by `token`.
+    pub fn new(token: String, keys: HashMap<String, KeyConfig>) -> Self {
+        let mut callers = keys
+            .into_iter()
+            .map(|(name, config)| (config.token.clone(), Caller::new(name, Arc::new(config))))
+            .collect::<Vec<_>>();
+
+        callers.push((
+            token.clone(),
+            Caller::new(
+                "default",
+                Arc::new(KeyConfig {
+                    token,
+                    admin: true,
+                    ..KeyConfig::default()
+                }),
+            ),
+        ));
+
+        Self(Arc::new(callers))
+    }
+
+    /// Finds the key matching `token`, comparing against every key in constant time so the
+    /// response time does not reveal how much of a token was correct.
+    fn authenticate(&self, token: &str) -> Option<Caller> {
+        let mut found = None;
+        for (candidate, caller) in self.0.iter() {
+            if bool::from(candidate.as_bytes().ct_eq(token.as_bytes())) {
+                found = Some(caller);
+            }
+        }
+
+        found.cloned()
+    }
+
+    /// Finds the key bound to a verified client certificate subject.
+    fn authenticate_client(&self, client_certificate: &ClientCertificate) -> Option<Caller> {
+        self.0
+            .iter()
+            .find(|(_, caller)| {
+                caller.config.client_subject.as_deref() == Some(&client_certificate.subject)
+            })
+            .map(|(_, caller)| caller.clone())
+    }
+}
+
+/// The least time between two prunings of the failed attempts, for short lockout windows.
+const MIN_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct FailedAttempts {
+    count: u32,
+    since: Instant,
+}
+
+/// Tracks failed authentication attempts per client address, locking out addresses that fail
+/// too often within the window.
+#[derive(Clone)]
+pub struct AuthLockout {
+    threshold: Option<u32>,
+    window: Duration,
+    failures: Arc<Mutex<HashMap<IpAddr, FailedAttempts>>>,
+}
+
+impl AuthLockout {
+    pub fn new(threshold: Option<u32>, window: Duration) -> Self {
+        let failures = Arc::new(Mutex::new(HashMap::<IpAddr, FailedAttempts>::new()));
+
+        // Addresses that failed once and never came back would otherwise stay forever.
+        if threshold.is_some() {
+            let failures = Arc::downgrade(&failures);
+            tokio::spawn(async move {
+                let mut prune = tokio::time::interval(window.max(MIN_PRUNE_INTERVAL));
+                loop {
+                    prune.tick().await;
+                    let Some(failures) = failures.upgrade() else {
+                        break;
+                    };
+                    failures
+                        .lock()
+                        .unwrap()
+                        .retain(|_, failed| failed.since.elapsed() < window);
+                }
+            });
+        }
+
+        Self {
+            threshold,
+            window,
+            failures,
+        }
+    }
+
+    fn is_locked(&self, ip: IpAddr) -> bool {
+        let Some(threshold) = self.threshold else {
+            return false;
+        };
+
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .is_some_and(|failed| failed.count >= threshold && failed.since.elapsed() < self.window)
+    }
+
+    fn record_failure(&self, ip: IpAddr) -> Option<u32> {
+        self.threshold?;
+
+        let mut failures = self.failures.lock().unwrap();
+        let failed = failures.entry(ip).or_insert(FailedAttempts {
+            count: 0,
+            since: Instant::now(),
+        });
+        // Attempts from before the window no longer count, whether or not they were pruned yet.
+        if failed.since.elapsed() >= self.window {
+            failed.count = 0;
+            failed.since = Instant::now();
+        }
+        failed.count += 1;
+        Some(failed.count)
+    }
+
+    fn clear(&self, ip: IpAddr) {
+        self.failures.lock().unwrap().remove(&ip);
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    ApiError::new(StatusCode::UNAUTHORIZED, "invalid_request_error", message)
+        .with_code("invalid_api_key")
+        .with_header(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))
+        .into_response()
+}
 
 pub async fn auth_middleware(
-    State(token): State<String>,
-    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
-    request: Request,
+    State(api_keys): State<ApiKeys>,
+    State(lockout): State<AuthLockout>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    mut request: Request,
     next: Next,
 ) -> Result<Response, Infallible> {
-    if authorization.token() != token {
-        return Ok((StatusCode::UNAUTHORIZED, "Unauthorized").into_response());
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    if ip.is_some_and(|ip| lockout.is_locked(ip)) {
+        tracing::warn!("rejected request from locked out address {ip:?}");
+
+        return Ok(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "invalid_request_error",
+            "Too many failed authentication attempts, try again later",
+        )
+        .with_code("locked_out")
+        .into_response());
     }
 
+    let client_caller = request
+        .extensions()
+        .get::<ClientCertificate>()
+        .and_then(|client_certificate| api_keys.authenticate_client(client_certificate));
+
+    let token = match authorization {
+        Some(TypedHeader(authorization)) => Some(authorization.token().to_string()),
+        None => take_websocket_token(request.headers_mut()),
+    };
+
+    let mut caller = match (client_caller, token) {
+        (Some(caller), _) => caller,
+        (None, Some(token)) => {
+            let Some(caller) = api_keys.authenticate(&token) else {
+                let attempts = ip.and_then(|ip| lockout.record_failure(ip));
+                tracing::warn!(
+                    "failed authentication attempt from {ip:?} ({attempts:?} in window)"
+                );
+
+                return Ok(unauthorized("Incorrect API key provided."));
+            };
+
+            caller
+        }
+        (None, None) => {
+            tracing::warn!("missing API key in request from {ip:?}");
+
+            return Ok(unauthorized(
+                "You didn't provide an API key. You need to provide your API key in an \
+                 Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY).",
+            ));
+        }
+    };
+
+    if let Some(ip) = ip {
+        lockout.clear(ip);
+    }
+
+    // Removed from the request so they cannot be picked up by anything logging headers.
+    let provider_api_key = request.headers_mut().remove(&PROVIDER_API_KEY);
+    caller.provider_api_keys = take_provider_api_keys(request.headers_mut());
+    if let Some(api_key) =
+        provider_api_key.and_then(|value| value.to_str().ok().map(str::to_string))
+    {
+        let Some(llm) = request
+            .headers()
+            .get(SupportedLlm::name())
+            .and_then(|value| SupportedLlm::decode(&mut std::iter::once(value)).ok())
+        else {
+            return Ok(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "A provider API key needs x-llm-provider to name its provider, or must be \
+                 passed as x-provider-api-key-<provider>.",
+            )
+            .with_code("provider_api_key_unpinned")
+            .into_response());
+        };
+
+        caller.provider_api_keys.pin(llm, api_key);
+    }
+
+    caller.request_id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    request.extensions_mut().insert(caller);
+
     Ok(next.run(request).await)
 }
+
+/// Lowers the priority of the authenticated caller to the one asked for in `x-llm-priority`,
+/// if any. A request may never raise the priority of its key.
+pub async fn priority_middleware(mut request: Request, next: Next) -> Response {
+    let Some(priority) = request.headers().get(&PRIORITY) else {
+        return next.run(request).await;
+    };
+
+    let Some(priority) = priority
+        .to_str()
+        .ok()
+        .and_then(|priority| Priority::try_from(priority).ok())
+    else {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "The x-llm-priority header must be one of interactive, standard or batch.",
+        )
+        .with_code("invalid_priority")
+        .into_response();
+    };
+
+    if let Some(caller) = request.extensions_mut().get_mut::<Caller>() {
+        caller.priority = caller.priority.max(priority);
+    }
+
+    next.run(request).await
+}
+
+fn take_provider_api_keys(headers: &mut HeaderMap) -> ProviderApiKeys {
+    let mut provider_api_keys = ProviderApiKeys::default();
+    for llm in SupportedLlm::ALL {
+        let name = format!("{PROVIDER_API_KEY_PREFIX}{llm}");
+        if let Some(api_key) = headers
+            .remove(name.as_str())
+            .and_then(|value| value.to_str().ok().map(str::to_string))
+        {
+            provider_api_keys.insert(llm, api_key);
+        }
+    }
+
+    provider_api_keys
+}
+
+/// Takes the token offered as a `bearer.<token>` WebSocket subprotocol, the only way browsers
+/// have of authenticating a WebSocket. It is removed from the offered subprotocols, so that it
+/// is neither negotiated nor echoed back.
+///
+/// Only WebSocket upgrade requests may authenticate this way.
+fn take_websocket_token(headers: &mut HeaderMap) -> Option<String> {
+    let is_upgrade = headers
+        .get(header::UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    if !is_upgrade {
+        return None;
+    }
+
+    let offered = headers.get(header::SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let (tokens, protocols): (Vec<_>, Vec<_>) = offered
+        .split(',')
+        .map(str::trim)
+        .partition(|protocol| protocol.starts_with(WEBSOCKET_BEARER));
+    let token = tokens.first()?[WEBSOCKET_BEARER.len()..].to_string();
+
+    match HeaderValue::from_str(&protocols.join(", ")) {
+        Ok(protocols) if !protocols.is_empty() => {
+            headers.insert(header::SEC_WEBSOCKET_PROTOCOL, protocols);
+        }
+        _ => {
+            headers.remove(header::SEC_WEBSOCKET_PROTOCOL);
+        }
+    }
+
+    Some(token)
+}
+
+/// Restricts the handlers taking it to admin keys. Requires [`auth_middleware`] to have run.
+pub struct AdminKey;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminKey {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if !parts
+            .extensions
+            .get::<Caller>()
+            .is_some_and(|caller| caller.config.admin)
+        {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "invalid_request_error",
+                "This key may not use the admin API.",
+            )
+            .with_code("not_admin"));
+        }
+
+        Ok(Self)
+    }
+}
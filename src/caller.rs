@@ -0,0 +1,112 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{
+    config::KeyConfig,
+    llm_delegate::{DelegateError, Priority, SupportedLlm},
+};
+
+/// Provider API keys supplied by the caller for a single request, each for its own provider.
+///
+/// Deliberately opaque: it is neither printable nor serialisable, so it cannot end up in logs.
+#[derive(Clone, Default)]
+pub struct ProviderApiKeys {
+    keys: HashMap<SupportedLlm, String>,
+    pinned: Option<SupportedLlm>,
+}
+
+impl ProviderApiKeys {
+    pub fn insert(&mut self, llm: SupportedLlm, api_key: String) {
+        self.keys.insert(llm, api_key);
+    }
+
+    /// Adds a key given for the provider the request selected, which the request is then kept
+    /// to: it never falls back to another provider.
+    pub fn pin(&mut self, llm: SupportedLlm, api_key: String) {
+        self.insert(llm, api_key);
+        self.pinned = Some(llm);
+    }
+
+    /// The only provider the request may be served by, if it was pinned to one.
+    pub fn pinned(&self) -> Option<SupportedLlm> {
+        self.pinned
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl fmt::Debug for ProviderApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProviderApiKeys([redacted])")
+    }
+}
+
+/// The identity of the gateway key that authenticated the current request.
+#[derive(Clone)]
+pub struct Caller {
+    /// A stable identifier for the key, safe to persist and log.
+    pub key: String,
+    pub config: Arc<KeyConfig>,
+    pub provider_api_keys: ProviderApiKeys,
+    /// The queueing class of the request, from the key unless lowered by the request itself.
+    pub priority: Priority,
+    /// The `x-request-id` of the current request, passed on to the providers.
+    pub request_id: Option<String>,
+}
+
+impl Caller {
+    pub fn new(key: impl Into<String>, config: Arc<KeyConfig>) -> Self {
+        Self {
+            key: key.into(),
+            priority: config.priority,
+            config,
+            provider_api_keys: ProviderApiKeys::default(),
+            request_id: None,
+        }
+    }
+
+    /// The gateway itself, calling providers on its own behalf with its own credentials, its
+    /// usage recorded under `key` rather than any client's.
+    pub fn internal(key: impl Into<String>) -> Self {
+        Self::new(
+            key,
+            Arc::new(KeyConfig {
+                priority: Priority::Batch,
+                ..KeyConfig::default()
+            }),
+        )
+    }
+
+    /// Whether this key may use the given model or alias.
+    pub fn allows(&self, model: &str) -> bool {
+        self.config.aliases.contains_key(model)
+            || self
+                .config
+                .models
+                .as_ref()
+                .is_none_or(|models| models.iter().any(|m| m == model))
+    }
+
+    /// The API key to call `llm` with instead of the gateway's own, either supplied with the
+    /// request or stored against this key.
+    ///
+    /// A request supplying keys is never served with the gateway's: routing it to a provider it
+    /// has no key for fails, rather than billing the gateway or sending its keys elsewhere.
+    pub fn provider_api_key(&self, llm: SupportedLlm) -> Result<Option<&str>, DelegateError> {
+        if self.provider_api_keys.is_empty() {
+            return Ok(self.config.provider_keys.get(&llm).map(String::as_str));
+        }
+
+        if !self.config.byok {
+            return Err(DelegateError::ByokNotAllowed);
+        }
+
+        self.provider_api_keys
+            .keys
+            .get(&llm)
+            .or_else(|| self.config.provider_keys.get(&llm))
+            .map(|api_key| Some(api_key.as_str()))
+            .ok_or_else(|| DelegateError::ByokMissing(llm.to_string()))
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    audit_log::AuditConfig,
+    llm_delegate::{
+        CatalogEntry, CircuitBreakerConfig, ConcurrencyConfig, OverflowConfig, PoolConfig,
+        Priority, PromptCachingConfig, Route, StreamingMode, SupportedLlm, Timeouts,
+    },
+    response_cache::CacheConfig,
+    resumable_streams::ResumableStreamsConfig,
+};
+
+/// Settings for a single gateway key.
+#[derive(Default, Deserialize)]
+pub struct KeyConfig {
+    /// The bearer token presented by clients using this key.
+    pub token: String,
+    /// Whether this key may use the `/admin` endpoints.
+    #[serde(default)]
+    pub admin: bool,
+    /// Models and aliases this key may use. All models are allowed when omitted.
+    #[serde(default)]
+    pub models: Option<Vec<String>>,
+    /// Aliases only visible to this key, taking precedence over the global ones.
+    #[serde(default)]
+    pub aliases: HashMap<String, Route>,
+    /// Whether callers may supply their own provider API keys, in `x-provider-api-key-<provider>`
+    /// headers or in `x-provider-api-key` for the provider named by `x-llm-provider`.
+    #[serde(default)]
+    pub byok: bool,
+    /// Provider API keys used for this key instead of the gateway's own.
+    #[serde(default)]
+    pub provider_keys: HashMap<SupportedLlm, String>,
+    /// Subject of a client certificate that authenticates as this key over mutual TLS.
+    #[serde(default)]
+    pub client_subject: Option<String>,
+    /// The queueing class of requests made with this key. Requests may lower it in the
+    /// `x-llm-priority` header, but never raise it.
+    #[serde(default)]
+    pub priority: Priority,
+    /// Whether requests made with this key are left out of the audit log, for sensitive tenants.
+    #[serde(default)]
+    pub skip_audit: bool,
+    /// Whether responses to requests made with this key are cached, when a cache is configured.
+    #[serde(default)]
+    pub cache: bool,
+    /// Whether requests made with this key may also be served the response to a similar prior
+    /// request, when a semantic cache is configured.
+    #[serde(default)]
+    pub semantic_cache: bool,
+}
+
+/// Gateway configuration, loaded from a JSON file.
+#[derive(Default, Deserialize)]
+pub struct GatewayConfig {
+    /// Model aliases available to every key, each resolving to a target or a fallback chain.
+    #[serde(default)]
+    pub aliases: HashMap<String, Route>,
+    /// Additional gateway keys, by name.
+    #[serde(default)]
+    pub keys: HashMap<String, KeyConfig>,
+    /// When provider circuits open and how they recover.
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Providers whose calls are spread across several credentials or endpoints.
+    #[serde(default)]
+    pub pools: HashMap<SupportedLlm, PoolConfig>,
+    /// Models `auto` requests may be routed to. A built-in catalog is used when omitted.
+    #[serde(default)]
+    pub catalog: Option<Vec<CatalogEntry>>,
+    /// Time limits on the calls to each provider.
+    #[serde(default)]
+    pub timeouts: HashMap<SupportedLlm, Timeouts>,
+    /// Providers, or pools of endpoints, that only support streamed or only whole responses.
+    /// Every provider serves both kinds of callers either way.
+    #[serde(default)]
+    pub streaming: HashMap<SupportedLlm, StreamingMode>,
+    /// Caps on the calls in flight to each provider and model.
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    /// How requests larger than the context window of their model are handled.
+    #[serde(default)]
+    pub overflow: OverflowConfig,
+    /// Whether Anthropic prompt cache breakpoints are placed automatically.
+    #[serde(default)]
+    pub prompt_caching: PromptCachingConfig,
+    /// Where and how requests and their responses are audited. Auditing is off when omitted.
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+    /// Where and for how long responses are cached for the keys opting in. Caching is off when
+    /// omitted.
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// How long streamed completions can be resumed by clients that lost their connection.
+    /// Streams are not resumable when omitted.
+    #[serde(default)]
+    pub resumable_streams: Option<ResumableStreamsConfig>,
+}
+
+impl GatewayConfig {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+}
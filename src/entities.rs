@@ -1,12 +1,17 @@
 mod anthropic;
+mod anthropic_api;
+mod chunks;
 mod openai;
+mod stream_aggregator;
 
 use std::{collections::HashMap, pin::Pin};
 
+pub use anthropic_api::{MessagesError, MessagesRequest, MessagesResponse};
 use anyhow::Result;
 use futures::Stream;
+pub use stream_aggregator::StreamAggregator;
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "role")]
 #[serde(rename_all = "lowercase")]
 pub enum CompletionRequestMessage {
@@ -17,7 +22,7 @@ pub enum CompletionRequestMessage {
     Function(CompletionRequestFunctionMessage),
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionRequestFunctionMessage {
     /// The return value from the function call, to return to the model.
     pub content: Option<String>,
@@ -25,14 +30,14 @@ pub struct CompletionRequestFunctionMessage {
     pub name: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionRequestToolMessage {
     /// The contents of the tool message.
     pub content: String,
     pub tool_call_id: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionRequestAssistantMessage {
     /// The contents of the assistant message.
     pub content: Option<String>,
@@ -41,16 +46,42 @@ pub struct CompletionRequestAssistantMessage {
     pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<CompletionMessageToolCall>>,
+    /// Marks the end of a prompt prefix for Anthropic to cache. Ignored by the other providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<PromptCacheControl>,
 }
 
-#[derive(Default, serde::Serialize, serde::Deserialize)]
+/// An Anthropic cache breakpoint, given as the `cache_control` field of a message, a text content
+/// part or a tool. Everything up to and including it is cached for reuse by later requests.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct PromptCacheControl {
+    #[serde(rename = "type")]
+    pub kind: PromptCacheType,
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptCacheType {
+    #[default]
+    Ephemeral,
+}
+
+impl PromptCacheControl {
+    pub fn ephemeral() -> Self {
+        Self {
+            kind: PromptCacheType::Ephemeral,
+        }
+    }
+}
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum CompletionToolType {
     #[default]
     Function,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionMessageToolCall {
     /// The ID of the tool call.
     pub id: String,
@@ -61,7 +92,7 @@ pub struct CompletionMessageToolCall {
     pub function: FunctionCall,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct FunctionCall {
     /// The name of the function to call.
     pub name: String,
@@ -69,16 +100,19 @@ pub struct FunctionCall {
     pub arguments: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionRequestSystemMessage {
     /// The contents of the system message.
     pub content: String,
     /// An optional name for the participant. Provides the model information to differentiate between participants of the same role.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// Marks the end of a prompt prefix for Anthropic to cache. Ignored by the other providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<PromptCacheControl>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(untagged)]
 pub enum CompletionRequestUserMessageContent {
     /// The text contents of the message.
@@ -89,7 +123,7 @@ pub enum CompletionRequestUserMessageContent {
     Array(Vec<CompletionRequestMessageContentPart>),
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum CompletionRequestMessageContentPart {
@@ -97,12 +131,15 @@ pub enum CompletionRequestMessageContentPart {
     ImageUrl(CompletionRequestMessageContentPartImage),
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionRequestMessageContentPartText {
     pub text: String,
+    /// Marks the end of a prompt prefix for Anthropic to cache. Ignored by the other providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<PromptCacheControl>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default)]
+#[derive(Clone, serde::Serialize, serde::Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum ImageDetail {
     #[default]
@@ -111,7 +148,7 @@ pub enum ImageDetail {
     High,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ImageUrl {
     /// Either a URL of the image or the base64 encoded image data.
     pub url: String,
@@ -119,28 +156,31 @@ pub struct ImageUrl {
     pub detail: Option<ImageDetail>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionRequestMessageContentPartImage {
     pub image_url: ImageUrl,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionRequestUserMessage {
     /// The contents of the user message.
     pub content: CompletionRequestUserMessageContent,
     /// An optional name for the participant. Provides the model information to differentiate between participants of the same role.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// Marks the end of a prompt prefix for Anthropic to cache. Ignored by the other providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<PromptCacheControl>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum CompletionResponseFormatType {
     Text,
     JsonObject,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionResponseFormat {
     /// Setting to `json_object` enables JSON mode. This guarantees that the message the model generates is valid JSON.
     ///
@@ -154,7 +194,7 @@ pub struct CompletionResponseFormat {
     pub kind: CompletionResponseFormatType,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(untagged)]
 pub enum Stop {
     String(String),           // nullable: true
@@ -162,20 +202,23 @@ pub enum Stop {
 }
 
 /// Options for streaming response. Only set this when you set `stream: true`.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionStreamOptions {
     /// If set, an additional chunk will be streamed before the `data: [DONE]` message. The `usage` field on this chunk shows the token usage statistics for the entire request, and the `choices` field will always be an empty array. All other chunks will also include a `usage` field, but with a null value.
     pub include_usage: bool,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionTool {
     #[serde(rename = "type")]
     pub kind: CompletionToolType,
     pub function: FunctionObject,
+    /// Marks the end of a prompt prefix for Anthropic to cache. Ignored by the other providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<PromptCacheControl>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct FunctionObject {
     /// The name of the function to be called. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a maximum length of 64.
     pub name: String,
@@ -196,7 +239,7 @@ pub struct FunctionObject {
 /// Specifying a particular tool via `{"type": "function", "function": {"name": "my_function"}}` forces the model to call that tool.
 ///
 /// `none` is the default when no tools are present. `auto` is the default if tools are present.present.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum CompletionToolChoiceOption {
     None,
@@ -206,7 +249,7 @@ pub enum CompletionToolChoiceOption {
     Named(CompletionNamedToolChoice),
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionNamedToolChoice {
     /// The type of the tool. Currently, only `function` is supported.
     #[serde(rename = "type")]
@@ -215,13 +258,13 @@ pub struct CompletionNamedToolChoice {
     pub function: FunctionName,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct FunctionName {
     /// The name of the function to call.
     pub name: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CreateCompletionRequest {
     /// A list of messages comprising the conversation so far. [Example Python code](https://cookbook.openai.com/examples/how_to_format_inputs_to_chatgpt_models).
     pub messages: Vec<CompletionRequestMessage>, // min: 1
@@ -329,7 +372,7 @@ pub struct CreateCompletionRequest {
     pub user: Option<String>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum FinishReason {
     Stop,
@@ -339,13 +382,25 @@ pub enum FinishReason {
     FunctionCall,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::Length => "length",
+            Self::ToolCalls => "tool_calls",
+            Self::ContentFilter => "content_filter",
+            Self::FunctionCall => "function_call",
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ChoiceLogprobs {
     /// A list of message content tokens with log probability information.
     pub content: Option<Vec<CompletionTokenLogprob>>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionTokenLogprob {
     /// The token.
     pub token: String,
@@ -357,7 +412,7 @@ pub struct CompletionTokenLogprob {
     pub top_logprobs: Vec<TopLogprobs>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct TopLogprobs {
     /// The token.
     pub token: String,
@@ -367,7 +422,7 @@ pub struct TopLogprobs {
     pub bytes: Option<Vec<u8>>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     System,
@@ -377,7 +432,7 @@ pub enum Role {
     Function,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionResponseMessage {
     /// The contents of the message.
     pub content: Option<String>,
@@ -389,7 +444,7 @@ pub struct CompletionResponseMessage {
     pub role: Role,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Choice {
     /// The index of the choice in the list of choices.
     pub index: u32,
@@ -404,7 +459,7 @@ pub struct Choice {
 }
 
 /// Usage statistics for the completion request.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionUsage {
     /// Number of tokens in the prompt.
     pub prompt_tokens: u32,
@@ -412,9 +467,15 @@ pub struct CompletionUsage {
     pub completion_tokens: u32,
     /// Total number of tokens used in the request (prompt + completion).
     pub total_tokens: u32,
+    /// Prompt tokens written to the Anthropic prompt cache, included in `prompt_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_creation_input_tokens: Option<u32>,
+    /// Prompt tokens read from the Anthropic prompt cache, included in `prompt_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_read_input_tokens: Option<u32>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CreateCompletionResponse {
     /// A unique identifier for the chat completion.
     pub id: String,
@@ -434,7 +495,7 @@ pub struct CreateCompletionResponse {
     pub usage: Option<CompletionUsage>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct FunctionCallStream {
     /// The name of the function to call.
     pub name: Option<String>,
@@ -445,7 +506,7 @@ pub struct FunctionCallStream {
     pub arguments: Option<String>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionMessageToolCallChunk {
     pub index: i32,
     /// The ID of the tool call.
@@ -456,7 +517,7 @@ pub struct CompletionMessageToolCallChunk {
 }
 
 /// A chat completion delta generated by streamed model responses.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct CompletionStreamResponseDelta {
     /// The contents of the chunk message.
     pub content: Option<String>,
@@ -469,7 +530,7 @@ pub struct CompletionStreamResponseDelta {
     pub role: Option<Role>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ChoiceStream {
     /// The index of the choice in the list of choices.
     pub index: u32,
@@ -479,7 +540,7 @@ pub struct ChoiceStream {
     pub logprobs: Option<ChoiceLogprobs>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 /// Represents a streamed chunk of a chat completion response returned by model, based on the provided input.
 pub struct CreateCompletionStreamResponse {
     /// A unique identifier for the chat completion. Each chunk has the same ID.
@@ -506,7 +567,7 @@ pub type CompletionResponseStream =
     Pin<Box<dyn Stream<Item = Result<CreateCompletionStreamResponse>> + Send>>;
 
 /// Describes an OpenAI model offering that can be used with the API.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Model {
     /// The model identifier, which can be referenced in the API endpoints.
     pub id: String,
@@ -518,12 +579,72 @@ pub struct Model {
     pub owned_by: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ListModelResponse {
     pub object: String,
     pub data: Vec<Model>,
 }
 
+impl CreateCompletionRequest {
+    /// A rough count of the prompt tokens, messages and tool definitions alike, at four
+    /// characters to a token.
+    pub fn estimated_prompt_tokens(&self) -> u32 {
+        let messages = serde_json::to_string(&self.messages).map_or(0, |json| json.len());
+        let tools = self
+            .tools
+            .as_ref()
+            .and_then(|tools| serde_json::to_string(tools).ok())
+            .map_or(0, |json| json.len());
+
+        (messages + tools) as u32 / 4
+    }
+
+    /// The request as sent to be streamed on behalf of a caller wanting a whole response, asking
+    /// for the usage so that it is not lost in the aggregation.
+    pub fn into_streamed(mut self) -> Self {
+        self.stream = Some(true);
+        self.stream_options = Some(CompletionStreamOptions {
+            include_usage: true,
+        });
+        self
+    }
+
+    /// The request as sent for a whole response on behalf of a caller wanting a stream, along
+    /// with whether that stream should end with the usage.
+    pub fn into_unstreamed(mut self) -> (Self, bool) {
+        let include_usage = self
+            .stream_options
+            .take()
+            .is_some_and(|options| options.include_usage);
+        self.stream = None;
+        (self, include_usage)
+    }
+
+    /// The text of the last user message, unless it also holds images.
+    pub fn last_user_text(&self) -> Option<String> {
+        let user = self
+            .messages
+            .iter()
+            .rev()
+            .find_map(|message| match message {
+                CompletionRequestMessage::User(user) => Some(user),
+                _ => None,
+            })?;
+
+        match &user.content {
+            CompletionRequestUserMessageContent::Text(text) => Some(text.clone()),
+            CompletionRequestUserMessageContent::Array(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    CompletionRequestMessageContentPart::Text(part) => Some(part.text.as_str()),
+                    CompletionRequestMessageContentPart::ImageUrl(_) => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|texts| texts.join("\n")),
+        }
+    }
+}
+
 impl Default for ListModelResponse {
     fn default() -> Self {
         Self {
@@ -533,7 +654,61 @@ impl Default for ListModelResponse {
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+/// The text to embed, as a string, a list of strings, or tokens.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    String(String),
+    StringArray(Vec<String>),
+    IntegerArray(Vec<u32>),
+    ArrayOfIntegerArray(Vec<Vec<u32>>),
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct CreateEmbeddingRequest {
+    /// ID of the model to use.
+    pub model: String,
+    /// Input text to embed. Each input must not exceed the max input tokens for the model.
+    pub input: EmbeddingInput,
+    /// A unique identifier representing your end-user, which can help to monitor and detect abuse.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The number of dimensions the resulting output embeddings should have. Only supported in `text-embedding-3` and later models.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+}
+
+/// Represents an embedding vector returned by embedding endpoint.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Embedding {
+    /// The index of the embedding in the list of embeddings.
+    pub index: u32,
+    /// The object type, which is always "embedding".
+    pub object: String,
+    /// The embedding vector, which is a list of floats.
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct EmbeddingUsage {
+    /// The number of tokens used by the prompt.
+    pub prompt_tokens: u32,
+    /// The total number of tokens used by the request.
+    pub total_tokens: u32,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct CreateEmbeddingResponse {
+    pub object: String,
+    /// The name of the model used to generate the embedding.
+    pub model: String,
+    /// The list of embeddings generated by the model.
+    pub data: Vec<Embedding>,
+    /// The usage information for the request.
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct DeleteModelResponse {
     pub id: String,
     pub object: String,
@@ -19,6 +19,7 @@ impl From<CreateMessageRequest> for CreateCompletionRequest {
                 CompletionRequestSystemMessage {
                     content: system,
                     name: None,
+                    cache_control: None,
                 },
             )]
         } else {
@@ -39,6 +40,7 @@ impl From<CreateMessageRequest> for CreateCompletionRequest {
                             .join("\n"),
                     ),
                     name: None,
+                    cache_control: None,
                 }),
                 Role::Assistant => {
                     CompletionRequestMessage::Assistant(CompletionRequestAssistantMessage {
@@ -54,6 +56,7 @@ impl From<CreateMessageRequest> for CreateCompletionRequest {
                         ),
                         name: None,
                         tool_calls: None,
+                        cache_control: None,
                     })
                 } // Add mappings for other roles if needed
             }
@@ -86,6 +89,7 @@ impl From<CreateMessageRequest> for CreateCompletionRequest {
                             description: tool.description,
                             parameters: Some(tool.input_schema.properties.unwrap_or_default()),
                         },
+                        cache_control: None,
                     })
                     .collect()
             }),
@@ -0,0 +1,419 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Choice, CompletionMessageToolCall, CompletionRequestMessage,
+    CompletionRequestMessageContentPart, CompletionRequestUserMessageContent,
+    CompletionResponseMessage, CompletionToolChoiceOption, CompletionToolType, CompletionUsage,
+    CreateCompletionRequest, CreateCompletionResponse, FinishReason, FunctionCall, ImageUrl,
+    PromptCacheControl, Role, Stop,
+};
+
+/// A request to the Anthropic Messages API as sent over the wire, for what the SDK cannot
+/// express, such as prompt cache breakpoints.
+#[derive(Serialize)]
+pub struct MessagesRequest {
+    /// Left out on Vertex AI, where the model is part of the URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// Set on Vertex AI, where it is part of the body rather than a header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    anthropic_version: Option<&'static str>,
+    messages: Vec<Message>,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    system: Vec<ContentBlock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: &'static str,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<PromptCacheControl>,
+    },
+    Image {
+        source: ImageSource,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<PromptCacheControl>,
+    },
+    /// A tool call made by the assistant in an earlier turn.
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<PromptCacheControl>,
+    },
+    /// The result of a tool call, sent back as part of a user turn.
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<PromptCacheControl>,
+    },
+}
+
+impl ContentBlock {
+    fn text(text: String, cache_control: Option<PromptCacheControl>) -> Self {
+        Self::Text {
+            text,
+            cache_control,
+        }
+    }
+
+    fn tool_use(call: CompletionMessageToolCall) -> Self {
+        Self::ToolUse {
+            id: call.id,
+            // Anthropic expects an object, which the model may have failed to produce.
+            input: serde_json::from_str(&call.function.arguments)
+                .unwrap_or_else(|_| serde_json::json!({})),
+            name: call.function.name,
+            cache_control: None,
+        }
+    }
+
+    fn set_cache_control(&mut self, cache_control: PromptCacheControl) {
+        match self {
+            Self::Text {
+                cache_control: c, ..
+            }
+            | Self::Image {
+                cache_control: c, ..
+            }
+            | Self::ToolUse {
+                cache_control: c, ..
+            }
+            | Self::ToolResult {
+                cache_control: c, ..
+            } => *c = Some(cache_control),
+        }
+    }
+
+    /// Anthropic rejects empty text blocks.
+    fn is_empty_text(&self) -> bool {
+        matches!(self, Self::Text { text, .. } if text.is_empty())
+    }
+}
+
+/// Where the data of an image comes from: inline, from a `data:` URL, or fetched by Anthropic.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+impl From<ImageUrl> for ImageSource {
+    fn from(image_url: ImageUrl) -> Self {
+        let inline = image_url
+            .url
+            .strip_prefix("data:")
+            .and_then(|data_url| data_url.split_once(";base64,"));
+
+        match inline {
+            Some((media_type, data)) => Self::Base64 {
+                media_type: media_type.to_string(),
+                data: data.to_string(),
+            },
+            None => Self::Url { url: image_url.url },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Metadata {
+    user_id: String,
+}
+
+#[derive(Serialize)]
+struct Tool {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    input_schema: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<PromptCacheControl>,
+}
+
+#[derive(Serialize)]
+struct ToolChoice {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MessagesResponse {
+    id: String,
+    model: String,
+    content: Vec<ResponseBlock>,
+    stop_reason: Option<String>,
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    input_tokens: u32,
+    output_tokens: u32,
+    #[serde(default)]
+    cache_creation_input_tokens: Option<u32>,
+    #[serde(default)]
+    cache_read_input_tokens: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct MessagesError {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+impl MessagesError {
+    /// The error as reported to the delegate, led by its type, which tells whether it is worth
+    /// retrying.
+    pub fn describe(body: &str) -> String {
+        match serde_json::from_str::<Self>(body) {
+            Ok(Self { error }) => format!("{}: {}", error.kind, error.message),
+            Err(_) => body.to_string(),
+        }
+    }
+}
+
+impl MessagesRequest {
+    /// The request as Vertex AI expects it, along with the model it names in the URL.
+    pub fn into_vertex_ai(mut self, anthropic_version: &'static str) -> (String, Self) {
+        let model = self.model.take().unwrap_or_default();
+        self.anthropic_version = Some(anthropic_version);
+        (model, self)
+    }
+}
+
+impl From<CreateCompletionRequest> for MessagesRequest {
+    fn from(request: CreateCompletionRequest) -> Self {
+        let mut system = Vec::new();
+        let mut messages = Vec::<Message>::new();
+        for message in request.messages {
+            let (role, mut content, cache_control) = match message {
+                CompletionRequestMessage::System(message) => {
+                    system.push(ContentBlock::text(message.content, message.cache_control));
+                    continue;
+                }
+                CompletionRequestMessage::User(message) => {
+                    let content = match message.content {
+                        CompletionRequestUserMessageContent::Text(text) => {
+                            vec![ContentBlock::text(text, None)]
+                        }
+                        CompletionRequestUserMessageContent::Array(parts) => parts
+                            .into_iter()
+                            .map(|part| match part {
+                                CompletionRequestMessageContentPart::Text(part) => {
+                                    ContentBlock::text(part.text, part.cache_control)
+                                }
+                                CompletionRequestMessageContentPart::ImageUrl(part) => {
+                                    ContentBlock::Image {
+                                        source: part.image_url.into(),
+                                        cache_control: None,
+                                    }
+                                }
+                            })
+                            .collect(),
+                    };
+                    ("user", content, message.cache_control)
+                }
+                CompletionRequestMessage::Assistant(message) => (
+                    "assistant",
+                    message
+                        .content
+                        .into_iter()
+                        .map(|text| ContentBlock::text(text, None))
+                        .chain(
+                            message
+                                .tool_calls
+                                .into_iter()
+                                .flatten()
+                                .map(ContentBlock::tool_use),
+                        )
+                        .collect(),
+                    message.cache_control,
+                ),
+                CompletionRequestMessage::Tool(message) => (
+                    "user",
+                    vec![ContentBlock::ToolResult {
+                        tool_use_id: message.tool_call_id,
+                        content: message.content,
+                        cache_control: None,
+                    }],
+                    None,
+                ),
+                // Deprecated function results carry no call ID to answer.
+                CompletionRequestMessage::Function(_) => continue,
+            };
+
+            content.retain(|block| !block.is_empty_text());
+            // A breakpoint on a message marks the end of its content.
+            if let Some((last, cache_control)) = content.last_mut().zip(cache_control) {
+                last.set_cache_control(cache_control);
+            }
+            if content.is_empty() {
+                continue;
+            }
+
+            // Turns alternate, so the results of parallel tool calls share a single user turn.
+            match messages.last_mut() {
+                Some(last) if last.role == role => last.content.append(&mut content),
+                _ => messages.push(Message { role, content }),
+            }
+        }
+
+        Self {
+            model: Some(request.model),
+            anthropic_version: None,
+            messages,
+            max_tokens: request.max_tokens.unwrap_or(4096),
+            system,
+            metadata: request.user.map(|user_id| Metadata { user_id }),
+            stop_sequences: match request.stop {
+                Some(Stop::StringArray(stop)) => Some(stop),
+                Some(Stop::String(stop)) => Some(vec![stop]),
+                None => None,
+            },
+            temperature: request.temperature,
+            top_p: request.top_p,
+            tools: request
+                .tools
+                .into_iter()
+                .flatten()
+                .map(|tool| Tool {
+                    name: tool.function.name,
+                    description: tool.function.description,
+                    input_schema: tool
+                        .function
+                        .parameters
+                        .unwrap_or_else(|| serde_json::json!({ "type": "object" })),
+                    cache_control: tool.cache_control,
+                })
+                .collect(),
+            tool_choice: request.tool_choice.map(|choice| match choice {
+                CompletionToolChoiceOption::None => ToolChoice {
+                    kind: "none",
+                    name: None,
+                },
+                CompletionToolChoiceOption::Auto => ToolChoice {
+                    kind: "auto",
+                    name: None,
+                },
+                CompletionToolChoiceOption::Required => ToolChoice {
+                    kind: "any",
+                    name: None,
+                },
+                CompletionToolChoiceOption::Named(named) => ToolChoice {
+                    kind: "tool",
+                    name: Some(named.function.name),
+                },
+            }),
+        }
+    }
+}
+
+impl From<MessagesResponse> for CreateCompletionResponse {
+    fn from(response: MessagesResponse) -> Self {
+        let mut text = None::<String>;
+        let mut tool_calls = Vec::new();
+        for block in response.content {
+            match block {
+                ResponseBlock::Text { text: block } => {
+                    text.get_or_insert_with(String::new).push_str(&block)
+                }
+                ResponseBlock::ToolUse { id, name, input } => {
+                    tool_calls.push(CompletionMessageToolCall {
+                        id,
+                        kind: CompletionToolType::Function,
+                        function: FunctionCall {
+                            name,
+                            arguments: input.to_string(),
+                        },
+                    })
+                }
+                ResponseBlock::Other => {}
+            }
+        }
+
+        let usage = response.usage;
+        // Unlike OpenAI, Anthropic leaves the cached tokens out of the input tokens.
+        let prompt_tokens = usage.input_tokens
+            + usage.cache_creation_input_tokens.unwrap_or_default()
+            + usage.cache_read_input_tokens.unwrap_or_default();
+
+        CreateCompletionResponse {
+            id: response.id,
+            choices: vec![Choice {
+                index: 0,
+                message: CompletionResponseMessage {
+                    content: text,
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                    role: Role::Assistant,
+                },
+                finish_reason: response.stop_reason.as_deref().map(|reason| match reason {
+                    "max_tokens" => FinishReason::Length,
+                    "tool_use" => FinishReason::ToolCalls,
+                    _ => FinishReason::Stop,
+                }),
+                logprobs: None,
+            }],
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs() as u32,
+            model: response.model,
+            system_fingerprint: None,
+            object: "chat.completion".into(),
+            usage: Some(CompletionUsage {
+                prompt_tokens,
+                completion_tokens: usage.output_tokens,
+                total_tokens: prompt_tokens + usage.output_tokens,
+                cache_creation_input_tokens: usage.cache_creation_input_tokens,
+                cache_read_input_tokens: usage.cache_read_input_tokens,
+            }),
+        }
+    }
+}
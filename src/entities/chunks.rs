@@ -0,0 +1,201 @@
+use super::{
+    ChoiceStream, CompletionMessageToolCallChunk, CompletionResponseStream,
+    CompletionStreamResponseDelta, CreateCompletionResponse, CreateCompletionStreamResponse,
+    FunctionCallStream,
+};
+
+impl CreateCompletionResponse {
+    /// Splits the response into the chunks a provider would have streamed it as: the role, the
+    /// content a word at a time, each tool call, then the finish reason of every choice.
+    ///
+    /// The usage comes last, in a chunk without choices, when `include_usage` is set.
+    #[allow(deprecated)]
+    pub fn into_chunks(self, include_usage: bool) -> Vec<CreateCompletionStreamResponse> {
+        let chunk = |choices| CreateCompletionStreamResponse {
+            id: self.id.clone(),
+            choices,
+            created: self.created,
+            model: self.model.clone(),
+            system_fingerprint: self.system_fingerprint.clone(),
+            object: "chat.completion.chunk".to_string(),
+            usage: None,
+        };
+        let delta = |index, delta| ChoiceStream {
+            index,
+            delta,
+            finish_reason: None,
+            logprobs: None,
+        };
+        let empty = || CompletionStreamResponseDelta {
+            content: None,
+            function_call: None,
+            tool_calls: None,
+            role: None,
+        };
+
+        let mut chunks = Vec::new();
+        for choice in &self.choices {
+            let message = &choice.message;
+            chunks.push(chunk(vec![ChoiceStream {
+                logprobs: choice.logprobs.clone(),
+                ..delta(
+                    choice.index,
+                    CompletionStreamResponseDelta {
+                        role: Some(message.role.clone()),
+                        content: Some(String::new()),
+                        ..empty()
+                    },
+                )
+            }]));
+
+            for word in message.content.iter().flat_map(|c| c.split_inclusive(' ')) {
+                chunks.push(chunk(vec![delta(
+                    choice.index,
+                    CompletionStreamResponseDelta {
+                        content: Some(word.to_string()),
+                        ..empty()
+                    },
+                )]));
+            }
+
+            for (index, tool_call) in message.tool_calls.iter().flatten().enumerate() {
+                chunks.push(chunk(vec![delta(
+                    choice.index,
+                    CompletionStreamResponseDelta {
+                        tool_calls: Some(vec![CompletionMessageToolCallChunk {
+                            index: index as i32,
+                            id: Some(tool_call.id.clone()),
+                            r#type: Some(tool_call.kind.clone()),
+                            function: Some(FunctionCallStream {
+                                name: Some(tool_call.function.name.clone()),
+                                arguments: Some(tool_call.function.arguments.clone()),
+                            }),
+                        }]),
+                        ..empty()
+                    },
+                )]));
+            }
+        }
+
+        chunks.push(chunk(
+            self.choices
+                .iter()
+                .map(|choice| ChoiceStream {
+                    finish_reason: choice.finish_reason.clone(),
+                    ..delta(choice.index, empty())
+                })
+                .collect(),
+        ));
+
+        if include_usage {
+            if let Some(usage) = &self.usage {
+                chunks.push(CreateCompletionStreamResponse {
+                    usage: Some(usage.clone()),
+                    ..chunk(Vec::new())
+                });
+            }
+        }
+
+        chunks
+    }
+
+    /// The response as a stream of its [`Self::into_chunks`], for serving it to callers that
+    /// asked for a stream.
+    pub fn into_stream(self, include_usage: bool) -> CompletionResponseStream {
+        Box::pin(futures::stream::iter(
+            self.into_chunks(include_usage).into_iter().map(Ok),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serde_json::json;
+
+    use super::*;
+    use crate::entities::{FinishReason, StreamAggregator};
+
+    fn response() -> CreateCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": "It is sunny in Paris",
+                    "tool_calls": [
+                        {
+                            "id": "call_a",
+                            "type": "function",
+                            "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" },
+                        },
+                        {
+                            "id": "call_b",
+                            "type": "function",
+                            "function": { "name": "time", "arguments": "{}" },
+                        },
+                    ],
+                },
+                "finish_reason": "tool_calls",
+                "logprobs": null,
+            }],
+            "created": 1,
+            "model": "gpt-4o",
+            "system_fingerprint": "fp_1",
+            "object": "chat.completion",
+            "usage": { "prompt_tokens": 3, "completion_tokens": 4, "total_tokens": 7 },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn chunks_reassemble_into_the_response() {
+        let mut aggregator = StreamAggregator::new();
+        for chunk in response().into_chunks(true) {
+            aggregator.push(&chunk);
+        }
+
+        assert_eq!(
+            serde_json::to_value(aggregator.finish()).unwrap(),
+            serde_json::to_value(response()).unwrap()
+        );
+    }
+
+    #[test]
+    fn chunks_content_a_word_at_a_time_and_usage_only_when_asked() {
+        let chunks = response().into_chunks(false);
+        let content = chunks
+            .iter()
+            .flat_map(|chunk| &chunk.choices)
+            .filter_map(|choice| choice.delta.content.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(content, ["", "It ", "is ", "sunny ", "in ", "Paris"]);
+
+        let last = chunks.last().unwrap();
+        assert!(last.usage.is_none());
+        assert!(matches!(
+            last.choices[0].finish_reason,
+            Some(FinishReason::ToolCalls)
+        ));
+
+        let with_usage = response().into_chunks(true);
+        let last = with_usage.last().unwrap();
+        assert!(last.choices.is_empty());
+        assert_eq!(last.usage.as_ref().map(|usage| usage.total_tokens), Some(7));
+    }
+
+    #[tokio::test]
+    async fn streams_the_chunks() {
+        let streamed = response()
+            .into_stream(true)
+            .map(|chunk| serde_json::to_value(chunk.unwrap()).unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            json!(streamed),
+            serde_json::to_value(response().into_chunks(true)).unwrap()
+        );
+    }
+}
@@ -1,6 +1,13 @@
-use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use async_openai::types::{
+    CreateChatCompletionRequest, CreateChatCompletionResponse,
+    CreateEmbeddingRequest as OpenAiEmbeddingRequest,
+    CreateEmbeddingResponse as OpenAiEmbeddingResponse,
+};
 
-use super::{CreateCompletionRequest, CreateCompletionResponse};
+use super::{
+    CreateCompletionRequest, CreateCompletionResponse, CreateEmbeddingRequest,
+    CreateEmbeddingResponse,
+};
 
 impl From<CreateChatCompletionRequest> for CreateCompletionRequest {
     fn from(chat_request: CreateChatCompletionRequest) -> Self {
@@ -25,3 +32,15 @@ impl Into<CreateChatCompletionResponse> for CreateCompletionResponse {
         serde_json::from_value(serde_json::to_value(self).unwrap()).unwrap()
     }
 }
+
+impl From<CreateEmbeddingRequest> for OpenAiEmbeddingRequest {
+    fn from(request: CreateEmbeddingRequest) -> Self {
+        serde_json::from_value(serde_json::to_value(request).unwrap()).unwrap()
+    }
+}
+
+impl From<OpenAiEmbeddingResponse> for CreateEmbeddingResponse {
+    fn from(response: OpenAiEmbeddingResponse) -> Self {
+        serde_json::from_value(serde_json::to_value(response).unwrap()).unwrap()
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+
+use futures::StreamExt;
+
+use super::{
+    Choice, ChoiceLogprobs, CompletionMessageToolCall, CompletionResponseMessage,
+    CompletionResponseStream, CompletionToolType, CompletionUsage, CreateCompletionResponse,
+    CreateCompletionStreamResponse, FinishReason, FunctionCall, Role,
+};
+
+#[derive(Default)]
+struct ChoiceState {
+    role: Option<Role>,
+    content: Option<String>,
+    tool_calls: BTreeMap<i32, CompletionMessageToolCall>,
+    finish_reason: Option<FinishReason>,
+    logprobs: Option<ChoiceLogprobs>,
+}
+
+/// Reassembles the chunks of a streamed completion into the response it stands for.
+#[derive(Default)]
+pub struct StreamAggregator {
+    id: String,
+    created: u32,
+    model: String,
+    system_fingerprint: Option<String>,
+    usage: Option<CompletionUsage>,
+    choices: BTreeMap<u32, ChoiceState>,
+}
+
+impl StreamAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: &CreateCompletionStreamResponse) {
+        self.id.clone_from(&chunk.id);
+        self.created = chunk.created;
+        self.model.clone_from(&chunk.model);
+        if chunk.system_fingerprint.is_some() {
+            self.system_fingerprint
+                .clone_from(&chunk.system_fingerprint);
+        }
+        if chunk.usage.is_some() {
+            self.usage.clone_from(&chunk.usage);
+        }
+
+        for choice in &chunk.choices {
+            let state = self.choices.entry(choice.index).or_default();
+            if choice.delta.role.is_some() {
+                state.role.clone_from(&choice.delta.role);
+            }
+            if let Some(content) = &choice.delta.content {
+                state
+                    .content
+                    .get_or_insert_with(String::new)
+                    .push_str(content);
+            }
+            for tool_call in choice.delta.tool_calls.iter().flatten() {
+                let call = state.tool_calls.entry(tool_call.index).or_insert_with(|| {
+                    CompletionMessageToolCall {
+                        id: String::new(),
+                        kind: CompletionToolType::Function,
+                        function: FunctionCall {
+                            name: String::new(),
+                            arguments: String::new(),
+                        },
+                    }
+                });
+                if let Some(id) = &tool_call.id {
+                    call.id.clone_from(id);
+                }
+                if let Some(function) = &tool_call.function {
+                    if let Some(name) = &function.name {
+                        call.function.name.push_str(name);
+                    }
+                    if let Some(arguments) = &function.arguments {
+                        call.function.arguments.push_str(arguments);
+                    }
+                }
+            }
+            if choice.finish_reason.is_some() {
+                state.finish_reason.clone_from(&choice.finish_reason);
+            }
+            if let Some(content) = choice.logprobs.as_ref().and_then(|l| l.content.as_ref()) {
+                state
+                    .logprobs
+                    .get_or_insert(ChoiceLogprobs {
+                        content: Some(Vec::new()),
+                    })
+                    .content
+                    .get_or_insert_with(Vec::new)
+                    .extend(content.iter().cloned());
+            }
+        }
+    }
+
+    /// Reads a stream to its end, failing with its first error.
+    pub async fn collect(
+        mut stream: CompletionResponseStream,
+    ) -> anyhow::Result<CreateCompletionResponse> {
+        let mut aggregator = Self::new();
+        while let Some(chunk) = stream.next().await {
+            aggregator.push(&chunk?);
+        }
+
+        Ok(aggregator.finish())
+    }
+
+    pub fn finish(self) -> CreateCompletionResponse {
+        CreateCompletionResponse {
+            id: self.id,
+            choices: self
+                .choices
+                .into_iter()
+                .map(|(index, state)| Choice {
+                    index,
+                    message: CompletionResponseMessage {
+                        content: state.content,
+                        tool_calls: (!state.tool_calls.is_empty())
+                            .then(|| state.tool_calls.into_values().collect()),
+                        role: state.role.unwrap_or(Role::Assistant),
+                    },
+                    finish_reason: state.finish_reason,
+                    logprobs: state.logprobs,
+                })
+                .collect(),
+            created: self.created,
+            model: self.model,
+            system_fingerprint: self.system_fingerprint,
+            object: "chat.completion".to_string(),
+            usage: self.usage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn chunk(choices: serde_json::Value) -> CreateCompletionStreamResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "choices": choices,
+            "created": 1,
+            "model": "gpt-4o",
+            "system_fingerprint": null,
+            "object": "chat.completion.chunk",
+            "usage": null,
+        }))
+        .unwrap()
+    }
+
+    fn tool_call(
+        index: i32,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: &str,
+    ) -> serde_json::Value {
+        json!([{
+            "index": 0,
+            "delta": {
+                "tool_calls": [{
+                    "index": index,
+                    "id": id,
+                    "type": id.map(|_| "function"),
+                    "function": { "name": name, "arguments": arguments },
+                }],
+            },
+            "finish_reason": null,
+        }])
+    }
+
+    #[test]
+    fn merges_tool_call_deltas_by_index() {
+        let mut aggregator = StreamAggregator::new();
+        for chunk in [
+            chunk(json!([{ "index": 0, "delta": { "role": "assistant" }, "finish_reason": null }])),
+            chunk(tool_call(0, Some("call_a"), Some("weather"), "")),
+            chunk(tool_call(1, Some("call_b"), Some("time"), "")),
+            chunk(tool_call(0, None, None, "{\"city\":")),
+            chunk(tool_call(1, None, None, "{\"zone\":\"CET\"}")),
+            chunk(tool_call(0, None, None, "\"Paris\"}")),
+            chunk(json!([{ "index": 0, "delta": {}, "finish_reason": "tool_calls" }])),
+        ] {
+            aggregator.push(&chunk);
+        }
+
+        let response = serde_json::to_value(aggregator.finish()).unwrap();
+        assert_eq!(
+            response["choices"][0]["message"]["tool_calls"],
+            json!([
+                {
+                    "id": "call_a",
+                    "type": "function",
+                    "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" },
+                },
+                {
+                    "id": "call_b",
+                    "type": "function",
+                    "function": { "name": "time", "arguments": "{\"zone\":\"CET\"}" },
+                },
+            ])
+        );
+        assert_eq!(response["choices"][0]["message"]["content"], json!(null));
+        assert_eq!(response["choices"][0]["finish_reason"], json!("tool_calls"));
+    }
+
+    #[test]
+    fn keeps_choices_apart_and_the_usage_of_the_last_chunk() {
+        let mut aggregator = StreamAggregator::new();
+        for chunk in [
+            chunk(json!([
+                { "index": 0, "delta": { "role": "assistant", "content": "Hel" }, "finish_reason": null },
+                { "index": 1, "delta": { "role": "assistant", "content": "Bon" }, "finish_reason": null },
+            ])),
+            chunk(json!([
+                { "index": 1, "delta": { "content": "jour" }, "finish_reason": "stop" },
+                { "index": 0, "delta": { "content": "lo" }, "finish_reason": "stop" },
+            ])),
+            CreateCompletionStreamResponse {
+                usage: Some(CompletionUsage {
+                    prompt_tokens: 3,
+                    completion_tokens: 4,
+                    total_tokens: 7,
+                    cache_creation_input_tokens: None,
+                    cache_read_input_tokens: None,
+                }),
+                ..chunk(json!([]))
+            },
+        ] {
+            aggregator.push(&chunk);
+        }
+
+        let response = serde_json::to_value(aggregator.finish()).unwrap();
+        assert_eq!(response["choices"][0]["message"]["content"], json!("Hello"));
+        assert_eq!(
+            response["choices"][1]["message"]["content"],
+            json!("Bonjour")
+        );
+        assert_eq!(response["usage"]["total_tokens"], json!(7));
+    }
+
+    #[tokio::test]
+    async fn collect_fails_with_the_first_error() {
+        let stream: CompletionResponseStream = Box::pin(futures::stream::iter([
+            Ok(chunk(
+                json!([{ "index": 0, "delta": { "content": "Hi" }, "finish_reason": null }]),
+            )),
+            Err(anyhow::anyhow!("connection reset")),
+        ]));
+
+        let error = StreamAggregator::collect(stream).await.err().unwrap();
+        assert_eq!(error.to_string(), "connection reset");
+    }
+}
@@ -0,0 +1,112 @@
+use axum::{
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    response::{AppendHeaders, IntoResponse, Response},
+    Json,
+};
+
+use crate::llm_delegate::{DelegateError, UpstreamTimeout};
+
+/// An error returned to clients in the OpenAI error format.
+pub struct ApiError {
+    status: StatusCode,
+    kind: &'static str,
+    code: Option<&'static str>,
+    message: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            kind,
+            code: None,
+            message: message.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// The body of the error, also sent over the transports that have no status line.
+    pub fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "message": self.message,
+            "type": self.kind,
+            "param": null,
+            "code": self.code,
+        })
+    }
+}
+
+impl From<DelegateError> for ApiError {
+    fn from(error: DelegateError) -> Self {
+        let (status, code) = match error {
+            DelegateError::ModelNotFound(_) => (StatusCode::NOT_FOUND, "model_not_found"),
+            DelegateError::ModelNotAllowed(_) => (StatusCode::FORBIDDEN, "model_not_allowed"),
+            DelegateError::ByokNotAllowed => {
+                (StatusCode::FORBIDDEN, "provider_api_key_not_allowed")
+            }
+            DelegateError::ByokMissing(_) => (StatusCode::FORBIDDEN, "provider_api_key_missing"),
+            DelegateError::ByokNotSupported(_) => {
+                (StatusCode::BAD_REQUEST, "provider_api_key_not_supported")
+            }
+            DelegateError::NoEligibleModel => (StatusCode::BAD_REQUEST, "no_eligible_model"),
+            DelegateError::ContextOverflow(..) => {
+                (StatusCode::BAD_REQUEST, "context_length_exceeded")
+            }
+            DelegateError::EmbeddingsNotSupported(_) => {
+                (StatusCode::BAD_REQUEST, "embeddings_not_supported")
+            }
+            DelegateError::Overloaded(_, retry_after) => {
+                return Self::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "api_error",
+                    error.to_string(),
+                )
+                .with_code("overloaded")
+                .with_header(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            }
+            DelegateError::CircuitOpen(_) => {
+                return Self::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "api_error",
+                    error.to_string(),
+                )
+                .with_code("circuit_open");
+            }
+        };
+
+        Self::new(status, "invalid_request_error", error.to_string()).with_code(code)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        if error.is::<UpstreamTimeout>() {
+            return Self::new(StatusCode::GATEWAY_TIMEOUT, "api_error", error.to_string())
+                .with_code("timeout");
+        }
+
+        match error.downcast::<DelegateError>() {
+            Ok(error) => error.into(),
+            Err(error) => Self::new(StatusCode::BAD_GATEWAY, "api_error", error.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.body() });
+
+        (self.status, AppendHeaders(self.headers), Json(body)).into_response()
+    }
+}
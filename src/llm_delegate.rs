@@ -1,101 +1,1018 @@
+mod auto_router;
+mod concurrency_limiter;
+mod context_window;
+mod delegate_error;
+mod hedge;
 mod llm_provider;
+mod model_target;
+mod prompt_caching;
+mod retry_policy;
+mod route;
 mod supported_llm;
+mod timeouts;
+mod upstream_error;
 
-use std::sync::Arc;
+use std::{collections::HashMap, future::Future, sync::Arc, time::Instant};
 
 use anyhow::bail;
-use futures::future::join_all;
-use llm_provider::LlmProviderMap;
+pub use auto_router::{default_catalog, CatalogEntry, RoutingConstraints};
+use auto_router::{AutoRouter, AUTO_MODEL};
+use concurrency_limiter::ConcurrencyLimiter;
+pub use concurrency_limiter::{ConcurrencyConfig, Priority};
+use context_window::ContextWindows;
+pub use context_window::OverflowConfig;
+pub use delegate_error::DelegateError;
+use futures::{
+    future::{join_all, OptionFuture},
+    StreamExt,
+};
+use hedge::{hedge, Secondary};
+use llm_provider::{AnyLlmProvider, CircuitBreakers, LlmProviderMap};
+pub use llm_provider::{
+    CircuitBreakerConfig, CircuitState, CircuitStatus, PoolConfig, PoolMemberStatus, StreamingMode,
+};
+pub use model_target::ModelTarget;
+pub use prompt_caching::PromptCachingConfig;
+pub use retry_policy::RetryPolicy;
+pub use route::{Route, Sticky};
 pub use supported_llm::SupportedLlm;
+use timeouts::within;
+pub use timeouts::{Timeouts, UpstreamTimeout};
+use tokio::sync::oneshot;
+use tracing::Instrument;
+pub use upstream_error::error_type;
+use upstream_error::{is_healthy, is_retryable, retry_after, should_fall_back};
 
-use crate::entities::{
-    CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse, ListModelResponse,
+use crate::{
+    audit_log::{AuditLog, PendingAudit},
+    caller::Caller,
+    entities::{
+        CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse,
+        CreateEmbeddingRequest, CreateEmbeddingResponse, EmbeddingInput, ListModelResponse, Model,
+    },
+    metrics::Metrics,
+    response_cache::{CacheControl, CacheEntry, CacheStatus, CachedResponse, ResponseCache},
+    shadow_store::{ShadowRecord, ShadowStore},
+    telemetry,
+    usage_ledger::{UsageLedger, UsageRecord},
 };
 
-use super::secret_manager::SecretManagerProvider;
+use super::secret_manager::{Overlay, SecretManagerProvider};
+
+/// The key shadow traffic is recorded under, kept apart from the usage of the clients whose
+/// requests are mirrored.
+pub const SHADOW_CALLER: &str = "gateway.shadow";
+
+/// The key the embeddings of the semantic cache are recorded under.
+pub const SEMANTIC_CACHE_CALLER: &str = "gateway.semantic_cache";
 
 #[derive(Clone)]
 pub struct LlmDelegate {
     secret_manager: Arc<dyn SecretManagerProvider>,
     llm_provider_map: Arc<LlmProviderMap>,
+    usage_ledger: UsageLedger,
+    metrics: Metrics,
+    shadow_store: ShadowStore,
+    audit_log: Option<AuditLog>,
+    response_cache: Option<ResponseCache>,
+    aliases: Arc<HashMap<String, Route>>,
+    retry_policy: RetryPolicy,
+    auto_router: Arc<AutoRouter>,
+    concurrency_limiter: Arc<ConcurrencyLimiter>,
+    context_windows: Arc<ContextWindows>,
+    prompt_caching: PromptCachingConfig,
+}
+
+/// How the delegate routes, retries and balances requests.
+pub struct DelegateConfig {
+    pub aliases: HashMap<String, Route>,
+    pub retry_policy: RetryPolicy,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub pools: HashMap<SupportedLlm, PoolConfig>,
+    pub catalog: Vec<CatalogEntry>,
+    pub timeouts: HashMap<SupportedLlm, Timeouts>,
+    pub streaming: HashMap<SupportedLlm, StreamingMode>,
+    pub concurrency: ConcurrencyConfig,
+    pub overflow: OverflowConfig,
+    pub prompt_caching: PromptCachingConfig,
+}
+
+/// A response along with the target that actually produced it.
+pub struct Served<T> {
+    pub target: ModelTarget,
+    pub response: T,
+    /// Whether the response came from the cache, if the request was cacheable at all.
+    pub cache: Option<CacheStatus>,
 }
 
 impl LlmDelegate {
-    pub fn new(secret_manager: Arc<dyn SecretManagerProvider>) -> Self {
+    pub fn new(
+        secret_manager: Arc<dyn SecretManagerProvider>,
+        usage_ledger: UsageLedger,
+        metrics: Metrics,
+        shadow_store: ShadowStore,
+        audit_log: Option<AuditLog>,
+        response_cache: Option<ResponseCache>,
+        config: DelegateConfig,
+    ) -> Self {
         Self {
             secret_manager,
-            llm_provider_map: Arc::new(LlmProviderMap::default()),
+            llm_provider_map: Arc::new(LlmProviderMap::new(
+                config.circuit_breaker,
+                config.pools,
+                config.timeouts,
+                config.streaming,
+            )),
+            usage_ledger,
+            metrics,
+            shadow_store,
+            audit_log,
+            response_cache,
+            aliases: Arc::new(config.aliases),
+            retry_policy: config.retry_policy,
+            auto_router: Arc::new(AutoRouter::new(config.catalog)),
+            concurrency_limiter: Arc::new(ConcurrencyLimiter::new(config.concurrency)),
+            context_windows: Arc::new(ContextWindows::new(config.overflow)),
+            prompt_caching: config.prompt_caching,
         }
     }
 
-    pub async fn completion(
+    /// Resolves the requested model, or alias, to the route serving it.
+    ///
+    /// Aliases defined on the caller's key take precedence over the global ones. Concrete
+    /// models require the provider to be given explicitly.
+    pub fn resolve(
+        &self,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        model: &str,
+    ) -> Result<Route, DelegateError> {
+        if !caller.allows(model) {
+            return Err(DelegateError::ModelNotAllowed(model.to_string()));
+        }
+
+        if let Some(route) = caller
+            .config
+            .aliases
+            .get(model)
+            .or_else(|| self.aliases.get(model))
+        {
+            // A request pinned to a provider by its key never falls back to another one.
+            return match caller.provider_api_keys.pinned() {
+                Some(llm) => route
+                    .clone()
+                    .only(llm)
+                    .ok_or_else(|| DelegateError::ByokMissing(route.targets[0].llm.to_string())),
+                None => Ok(route.clone()),
+            };
+        }
+
+        llm.map(|llm| {
+            Route::from(ModelTarget {
+                llm,
+                model: model.to_string(),
+            })
+        })
+        .ok_or_else(|| DelegateError::ModelNotFound(model.to_string()))
+    }
+
+    /// Resolves the route for a request, picking the models automatically for `auto` ones and
+    /// assigning the client to a side of the route's splits.
+    ///
+    /// Requests too large for the context window of their model are then handled according to
+    /// the overflow policy, which may truncate them.
+    async fn route(
+        &self,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        request: &mut CreateCompletionRequest,
+        constraints: &RoutingConstraints,
+    ) -> Result<Route, DelegateError> {
+        let route = if request.model != AUTO_MODEL {
+            let route = self.resolve(caller, llm, &request.model)?;
+            let client = match route.sticky {
+                Sticky::Key => &caller.key,
+                Sticky::User => request.user.as_ref().unwrap_or(&caller.key),
+            };
+
+            route.split(&request.model, client)
+        } else {
+            self.auto_route(caller, llm, request, constraints).await?
+        };
+
+        self.context_windows
+            .fit(route, request, |target| caller.allows(&target.model))
+    }
+
+    /// Builds the route of an `auto` request out of the models the providers list.
+    async fn auto_route(
         &self,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        request: &CreateCompletionRequest,
+        constraints: &RoutingConstraints,
+    ) -> Result<Route, DelegateError> {
+        let available = match self.auto_router.available() {
+            Some(available) => available,
+            None => self.auto_router.set_available(
+                self.listed_models()
+                    .await
+                    .into_iter()
+                    .map(|(llm, model)| ModelTarget {
+                        llm,
+                        model: model.id,
+                    })
+                    .collect(),
+            ),
+        };
+
+        self.auto_router
+            .route(
+                &available,
+                request,
+                constraints,
+                self.llm_provider_map.circuit_breakers(),
+                |target| llm.is_none_or(|llm| llm == target.llm) && caller.allows(&target.model),
+            )
+            .ok_or(DelegateError::NoEligibleModel)
+    }
+
+    /// The circuit breakers a call to `target` goes through, unless it is made with the
+    /// caller's own credentials. Those have their own quotas, so their failures say nothing of
+    /// the gateway's, and one caller exhausting them must not open the circuit for everyone.
+    fn circuit_breakers(&self, caller: &Caller, target: &ModelTarget) -> Option<&CircuitBreakers> {
+        matches!(caller.provider_api_key(target.llm), Ok(None))
+            .then(|| self.llm_provider_map.circuit_breakers())
+    }
+
+    /// Returns the client for `llm`, built from the caller's own credentials when it supplied
+    /// any. Such clients are never cached.
+    async fn provider(
+        &self,
+        caller: &Caller,
         llm: SupportedLlm,
-        request: CreateCompletionRequest,
-    ) -> anyhow::Result<CreateCompletionResponse> {
+    ) -> anyhow::Result<Arc<dyn AnyLlmProvider>> {
+        let Some(api_key) = caller.provider_api_key(llm)? else {
+            return self
+                .llm_provider_map
+                .get(self.secret_manager.clone(), llm)
+                .await;
+        };
+
+        let secret_id = llm_provider::api_key_secret(llm)
+            .ok_or_else(|| DelegateError::ByokNotSupported(llm.to_string()))?;
+
+        LlmProviderMap::init(
+            Overlay::new(self.secret_manager.clone(), secret_id, api_key.to_string()),
+            llm,
+            self.llm_provider_map.timeouts(llm).connect(),
+        )
+        .await
+    }
+
+    /// Serves the request from the cache when possible, otherwise from the first target of its
+    /// route that succeeds, moving on to the next one only when the failure is retryable.
+    ///
+    /// On hedged routes, a target too slow to respond races the next one.
+    pub async fn completion(
+        &self,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        constraints: &RoutingConstraints,
+        cache_control: CacheControl,
+        mut request: CreateCompletionRequest,
+    ) -> anyhow::Result<Served<CreateCompletionResponse>> {
         if request.stream.is_some_and(|f| f) {
             bail!("streaming completions are not supported")
         }
 
-        Ok(self
-            .llm_provider_map
-            .get(self.secret_manager.clone(), llm)
-            .await?
-            .completion(request)
-            .await?)
+        // Keyed on the request as sent, before routing truncates it.
+        let cache = self.cache_entry(caller, llm, cache_control, &request);
+        let route = self.route(caller, llm, &mut request, constraints).await?;
+        let cache = self.embed(caller, cache).await;
+        if let Some(CachedResponse { target, response }) =
+            OptionFuture::from(cache.as_ref().map(CacheEntry::lookup))
+                .await
+                .flatten()
+        {
+            if let Some(mut audit) = self.audit(caller, &request) {
+                audit.succeed(&target, serde_json::to_value(&response).unwrap_or_default());
+            }
+
+            return Ok(Served {
+                target,
+                response,
+                cache: Some(CacheStatus::Hit),
+            });
+        }
+
+        let mut shadow = route
+            .shadow
+            .clone()
+            .map(|shadow| self.mirror(caller, &request, shadow));
+        let mut audit = self.audit(caller, &request);
+
+        let mut targets = route.targets.into_iter().peekable();
+        while let Some(target) = targets.next() {
+            let (secondary, result) = hedge(
+                self.retrying(caller, &target, || {
+                    self.attempt(caller, &target, request.clone())
+                }),
+                route
+                    .hedge_after
+                    .zip(targets.peek())
+                    .map(|(delay, secondary)| {
+                        (
+                            delay,
+                            self.retrying(caller, secondary, || {
+                                self.attempt(caller, secondary, request.clone())
+                            }),
+                        )
+                    }),
+            )
+            .await;
+            let target = self.hedged(target, secondary, &mut targets);
+
+            match result {
+                Ok(response) => {
+                    if let Some(shadow) = shadow.take() {
+                        let _ = shadow.send((target.clone(), serde_json::to_value(&response).ok()));
+                    }
+                    if let Some(audit) = &mut audit {
+                        audit.succeed(&target, serde_json::to_value(&response).unwrap_or_default());
+                    }
+                    if let Some(cache) = &cache {
+                        cache.store(&target, &response);
+                    }
+
+                    return Ok(Served {
+                        target,
+                        response,
+                        cache: cache.map(|_| CacheStatus::Miss),
+                    });
+                }
+                Err(e) if targets.peek().is_some() && should_fall_back(&e) => {
+                    tracing::warn!("{target} failed, falling back: {e}");
+                    self.metrics.count_fallback(&target, &caller.key);
+                }
+                Err(e) => {
+                    if let Some(audit) = &mut audit {
+                        audit.fail(&e);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Err(DelegateError::ModelNotFound(request.model).into())
     }
 
+    /// Streams the request from the first target of its route that accepts it, or replays it
+    /// from the cache. Falling back is only possible until the stream has been handed over.
     pub async fn completion_stream(
         &self,
-        llm: SupportedLlm,
-        request: CreateCompletionRequest,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        constraints: &RoutingConstraints,
+        cache_control: CacheControl,
+        mut request: CreateCompletionRequest,
+    ) -> anyhow::Result<Served<CompletionResponseStream>> {
+        let cache = self.cache_entry(caller, llm, cache_control, &request);
+        let route = self.route(caller, llm, &mut request, constraints).await?;
+        let cache = self.embed(caller, cache).await;
+        if let Some(CachedResponse { target, response }) =
+            OptionFuture::from(cache.as_ref().map(CacheEntry::lookup))
+                .await
+                .flatten()
+        {
+            let include_usage = request
+                .stream_options
+                .as_ref()
+                .is_some_and(|options| options.include_usage);
+            let response = response.into_stream(include_usage);
+            let response = match self.audit(caller, &request) {
+                Some(audit) => audit.stream(&target, response),
+                None => response,
+            };
+
+            return Ok(Served {
+                target,
+                response,
+                cache: Some(CacheStatus::Hit),
+            });
+        }
+
+        let mut shadow = route
+            .shadow
+            .clone()
+            .map(|shadow| self.mirror(caller, &request, shadow));
+        let mut audit = self.audit(caller, &request);
+
+        let mut targets = route.targets.into_iter().peekable();
+        while let Some(target) = targets.next() {
+            let (secondary, result) = hedge(
+                self.retrying(caller, &target, || {
+                    self.attempt_stream(caller, &target, request.clone())
+                }),
+                route
+                    .hedge_after
+                    .zip(targets.peek())
+                    .map(|(delay, secondary)| {
+                        (
+                            delay,
+                            self.retrying(caller, secondary, || {
+                                self.attempt_stream(caller, secondary, request.clone())
+                            }),
+                        )
+                    }),
+            )
+            .await;
+            let target = self.hedged(target, secondary, &mut targets);
+
+            match result {
+                Ok(response) => {
+                    if let Some(shadow) = shadow.take() {
+                        let _ = shadow.send((target.clone(), None));
+                    }
+                    let status = cache.as_ref().map(|_| CacheStatus::Miss);
+                    let response = match cache {
+                        Some(cache) => cache.stream(&target, response),
+                        None => response,
+                    };
+                    let response = match audit {
+                        Some(audit) => audit.stream(&target, response),
+                        None => response,
+                    };
+
+                    return Ok(Served {
+                        target,
+                        response,
+                        cache: status,
+                    });
+                }
+                Err(e) if targets.peek().is_some() && should_fall_back(&e) => {
+                    tracing::warn!("{target} failed, falling back: {e}");
+                    self.metrics.count_fallback(&target, &caller.key);
+                }
+                Err(e) => {
+                    if let Some(audit) = &mut audit {
+                        audit.fail(&e);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Err(DelegateError::ModelNotFound(request.model).into())
+    }
+
+    /// Embeds the input with the first target the requested model resolves to. Embeddings of
+    /// different models cannot be compared with one another, so there is no falling back.
+    pub async fn embeddings(
+        &self,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        request: CreateEmbeddingRequest,
+    ) -> anyhow::Result<Served<CreateEmbeddingResponse>> {
+        let route = self.resolve(caller, llm, &request.model)?;
+        let target = route
+            .targets
+            .into_iter()
+            .next()
+            .expect("routes have a target");
+        if !llm_provider::supports_embeddings(target.llm) {
+            return Err(DelegateError::EmbeddingsNotSupported(target.llm.to_string()).into());
+        }
+
+        let response = self
+            .retrying(caller, &target, || {
+                self.attempt_embeddings(caller, &target, request.clone())
+            })
+            .await?;
+
+        Ok(Served {
+            target,
+            response,
+            cache: None,
+        })
+    }
+
+    /// Sends a copy of the request to `shadow` in the background, and stores its response
+    /// alongside the one sent back to the client.
+    ///
+    /// The returned sender takes the target that served the client and its response, if it
+    /// was not streamed. Dropping it records the request as failed.
+    fn mirror(
+        &self,
+        caller: &Caller,
+        request: &CreateCompletionRequest,
+        shadow: ModelTarget,
+    ) -> oneshot::Sender<(ModelTarget, Option<serde_json::Value>)> {
+        let (sender, receiver) = oneshot::channel();
+
+        let delegate = self.clone();
+        let key = caller.key.clone();
+        let mut request = request.clone();
+        tokio::spawn(async move {
+            let timestamp = UsageRecord::now();
+            let model = request.model.clone();
+            let request_json = serde_json::to_value(&request).unwrap_or_default();
+
+            request.stream = None;
+            request.stream_options = None;
+
+            let started = Instant::now();
+            let result = delegate.attempt_shadow(&shadow, request).await;
+            let shadow_latency = started.elapsed();
+
+            // Mirrors are optional: one that could not get a slot in time is dropped rather
+            // than stored as a failure of the shadow target.
+            let error = result.as_ref().err();
+            if let Some(DelegateError::Overloaded(..)) =
+                error.and_then(|e| e.downcast_ref::<DelegateError>())
+            {
+                tracing::info!("dropped the mirror to {shadow}, which is too busy");
+                return;
+            }
+
+            let (primary, primary_response) = receiver
+                .await
+                .map_or((None, None), |(target, response)| (Some(target), response));
+
+            let (shadow_response, shadow_error) = match result {
+                Ok(response) => (serde_json::to_value(&response).ok(), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+
+            delegate.shadow_store.record(ShadowRecord {
+                timestamp,
+                key,
+                model,
+                request: request_json,
+                primary,
+                primary_response,
+                shadow,
+                shadow_response,
+                shadow_error,
+                shadow_latency,
+            });
+        });
+
+        sender
+    }
+
+    /// The cache entry of a request, when the response cache is enabled.
+    fn cache_entry(
+        &self,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        cache_control: CacheControl,
+        request: &CreateCompletionRequest,
+    ) -> Option<CacheEntry> {
+        self.response_cache
+            .as_ref()?
+            .entry(caller, llm, cache_control, request)
+    }
+
+    /// Embeds the last user message of a request matched semantically, once it was routed, on
+    /// the gateway's own behalf: the embedding model is neither the caller's to choose nor to
+    /// pay for. Failures are only logged, as they merely cost the request its semantic cache
+    /// lookup.
+    async fn embed(&self, caller: &Caller, cache: Option<CacheEntry>) -> Option<CacheEntry> {
+        let mut cache = cache?;
+        let Some((target, input)) = cache.semantic_input() else {
+            return Some(cache);
+        };
+
+        let mut internal = Caller::internal(SEMANTIC_CACHE_CALLER);
+        internal.request_id = caller.request_id.clone();
+        let request = CreateEmbeddingRequest {
+            model: target.model.clone(),
+            input: EmbeddingInput::String(input.to_string()),
+            user: None,
+            dimensions: None,
+        };
+
+        match self.embeddings(&internal, Some(target.llm), request).await {
+            Ok(Served { response, .. }) => {
+                if let Some(embedding) = response.data.into_iter().next() {
+                    cache.set_embedding(embedding.embedding);
+                }
+            }
+            Err(e) => tracing::warn!("failed to embed the request for the semantic cache: {e}"),
+        }
+
+        Some(cache)
+    }
+
+    /// Starts auditing a request, when the audit log is enabled.
+    fn audit(&self, caller: &Caller, request: &CreateCompletionRequest) -> Option<PendingAudit> {
+        self.audit_log
+            .as_ref()
+            .and_then(|audit_log| audit_log.start(caller, request))
+    }
+
+    /// Returns the target that produced the result of a hedge, taking the secondary one out of
+    /// the remaining targets when it was sent.
+    fn hedged(
+        &self,
+        primary: ModelTarget,
+        secondary: Secondary,
+        targets: &mut impl Iterator<Item = ModelTarget>,
+    ) -> ModelTarget {
+        if secondary == Secondary::NotStarted {
+            return primary;
+        }
+
+        let hedge = targets.next().expect("hedges are sent to the next target");
+        tracing::info!("{primary} was slow, hedged with {hedge}");
+
+        match secondary {
+            Secondary::Won => hedge,
+            _ => primary,
+        }
+    }
+
+    /// Repeats `call` while it fails with retryable errors, as allowed by the retry policy.
+    async fn retrying<T, F, Fut>(
+        &self,
+        caller: &Caller,
+        target: &ModelTarget,
+        mut call: F,
+    ) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            match call().await {
+                Err(e) if is_retryable(&e) => {
+                    let Some(delay) = self.retry_policy.delay(retry, retry_after(&e)) else {
+                        return Err(e);
+                    };
+
+                    tracing::warn!("{target} failed, retrying in {delay:?}: {e}");
+                    self.metrics.count_retry(target, &caller.key);
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends the request to a single target, recording its usage.
+    async fn attempt(
+        &self,
+        caller: &Caller,
+        target: &ModelTarget,
+        mut request: CreateCompletionRequest,
+    ) -> anyhow::Result<CreateCompletionResponse> {
+        request.model = target.model.clone();
+        self.prompt_caching.place_breakpoints(target, &mut request);
+
+        // Held until the call returns.
+        let _permits = self
+            .concurrency_limiter
+            .acquire(target, caller.priority)
+            .await?;
+
+        let circuit_breakers = self.circuit_breakers(caller, target);
+        if circuit_breakers.is_some_and(|circuit_breakers| !circuit_breakers.acquire(target)) {
+            return Err(DelegateError::CircuitOpen(target.to_string()).into());
+        }
+
+        let mut usage = self
+            .usage_ledger
+            .start(&caller.key, target.llm, &target.model);
+        let started = Instant::now();
+
+        let span = telemetry::upstream_span("chat", target);
+        let timeouts = self.llm_provider_map.timeouts(target.llm);
+        let streaming = self.llm_provider_map.streaming(target.llm);
+        let call = within(timeouts.total(), "response", async {
+            let provider = self.provider(caller, target.llm).await?;
+            llm_provider::completion(provider.as_ref(), streaming, request).await
+        });
+        let result = telemetry::with_request_id(caller.request_id.clone(), call)
+            .instrument(span.clone())
+            .await;
+
+        if let Some(circuit_breakers) = circuit_breakers {
+            circuit_breakers.record(target, is_healthy(&result), started.elapsed());
+        }
+
+        match &result {
+            Ok(response) => {
+                if let Some(response_usage) = &response.usage {
+                    usage.set_tokens(
+                        response_usage.prompt_tokens,
+                        response_usage.completion_tokens,
+                    );
+                }
+                usage.succeed();
+
+                let finish_reasons = response
+                    .choices
+                    .iter()
+                    .filter_map(|choice| choice.finish_reason.clone())
+                    .collect::<Vec<_>>();
+                telemetry::record_response(
+                    &span,
+                    &response.id,
+                    &response.model,
+                    response.usage.as_ref(),
+                    &finish_reasons,
+                );
+            }
+            Err(e) => {
+                usage.fail();
+                telemetry::record_error(&span, e);
+            }
+        }
+
+        result
+    }
+
+    /// Sends a mirrored request to its shadow target on the gateway's own behalf, with its own
+    /// credentials and under [`SHADOW_CALLER`] rather than the client's key.
+    ///
+    /// It waits for permits as a batch request, behind the traffic clients rely on, and does
+    /// not count towards circuit breakers, which are there for that traffic.
+    async fn attempt_shadow(
+        &self,
+        target: &ModelTarget,
+        mut request: CreateCompletionRequest,
+    ) -> anyhow::Result<CreateCompletionResponse> {
+        let caller = Caller::internal(SHADOW_CALLER);
+        request.model = target.model.clone();
+        self.prompt_caching.place_breakpoints(target, &mut request);
+
+        // Held until the call returns.
+        let _permits = self
+            .concurrency_limiter
+            .acquire(target, caller.priority)
+            .await?;
+
+        let mut usage = self
+            .usage_ledger
+            .start(&caller.key, target.llm, &target.model);
+
+        let timeouts = self.llm_provider_map.timeouts(target.llm);
+        let streaming = self.llm_provider_map.streaming(target.llm);
+        let result = within(timeouts.total(), "response", async {
+            let provider = self.provider(&caller, target.llm).await?;
+            llm_provider::completion(provider.as_ref(), streaming, request).await
+        })
+        .instrument(telemetry::upstream_span("chat", target))
+        .await;
+
+        match &result {
+            Ok(response) => {
+                if let Some(response_usage) = &response.usage {
+                    usage.set_tokens(
+                        response_usage.prompt_tokens,
+                        response_usage.completion_tokens,
+                    );
+                }
+                usage.succeed();
+            }
+            Err(_) => usage.fail(),
+        }
+
+        result
+    }
+
+    /// Opens a stream from a single target, recording its usage once the stream ends.
+    ///
+    /// The first chunk is awaited before returning, so that a stream failing right away is
+    /// reported as a failed attempt rather than reaching the client.
+    async fn attempt_stream(
+        &self,
+        caller: &Caller,
+        target: &ModelTarget,
+        mut request: CreateCompletionRequest,
     ) -> anyhow::Result<CompletionResponseStream> {
-        Ok(self
-            .llm_provider_map
-            .get(self.secret_manager.clone(), llm)
-            .await?
-            .completion_stream(request)
-            .await?)
+        request.model = target.model.clone();
+        self.prompt_caching.place_breakpoints(target, &mut request);
+
+        let permits = self
+            .concurrency_limiter
+            .acquire(target, caller.priority)
+            .await?;
+
+        let circuit_breakers = self.circuit_breakers(caller, target);
+        if circuit_breakers.is_some_and(|circuit_breakers| !circuit_breakers.acquire(target)) {
+            return Err(DelegateError::CircuitOpen(target.to_string()).into());
+        }
+
+        let mut usage = self
+            .usage_ledger
+            .start(&caller.key, target.llm, &target.model);
+        usage.estimate_tokens(request.estimated_prompt_tokens(), 0);
+        let started = Instant::now();
+
+        let timeouts = self.llm_provider_map.timeouts(target.llm);
+        let streaming = self.llm_provider_map.streaming(target.llm);
+        let first_chunk = async {
+            let provider = self.provider(caller, target.llm).await?;
+            let mut stream =
+                llm_provider::completion_stream(provider.as_ref(), streaming, request).await?;
+
+            match stream.next().await {
+                Some(Err(e)) => Err(e),
+                first => Ok((first, stream)),
+            }
+        };
+        let span = telemetry::upstream_span("chat", target);
+        let call = within(
+            timeouts.total(),
+            "response",
+            within(timeouts.first_token(), "first token", first_chunk),
+        );
+        let result = telemetry::with_request_id(caller.request_id.clone(), call)
+            .instrument(span.clone())
+            .await;
+
+        if let Some(circuit_breakers) = circuit_breakers {
+            circuit_breakers.record(target, is_healthy(&result), started.elapsed());
+        }
+
+        let (first, mut stream) = result.inspect_err(|e| {
+            usage.fail();
+            telemetry::record_error(&span, e);
+        })?;
+        self.metrics
+            .observe_first_token(target, &caller.key, started.elapsed());
+        let deadline = timeouts.total().map(|total| (started + total, total));
+
+        // Dropping the stream, as happens when the client goes away, drops the upstream request
+        // with it and records the usage so far as cancelled. The span ends along with it.
+        Ok(Box::pin(async_stream::stream! {
+            let _permits = permits;
+            let mut response_ids = None;
+            let mut response_usage = None;
+            let mut finish_reasons = Vec::new();
+
+            let mut stream = futures::stream::iter(first).chain(&mut stream);
+            loop {
+                let item = match deadline {
+                    Some((deadline, total)) => tokio::time::timeout_at(deadline.into(), stream.next())
+                        .await
+                        .unwrap_or_else(|_| Some(Err(UpstreamTimeout { stage: "response", after: total }.into()))),
+                    None => stream.next().await,
+                };
+                let Some(item) = item else {
+                    break;
+                };
+
+                let timed_out = item.as_ref().is_err_and(|e| e.is::<UpstreamTimeout>());
+                match &item {
+                    Ok(chunk) => {
+                        match &chunk.usage {
+                            Some(chunk_usage) => usage.set_tokens(chunk_usage.prompt_tokens, chunk_usage.completion_tokens),
+                            None => usage.count_chunk(),
+                        }
+
+                        response_ids.get_or_insert_with(|| (chunk.id.clone(), chunk.model.clone()));
+                        response_usage = chunk.usage.clone().or(response_usage);
+                        finish_reasons.extend(chunk.choices.iter().filter_map(|choice| choice.finish_reason.clone()));
+                    }
+                    Err(e) => {
+                        usage.fail();
+                        telemetry::record_error(&span, e);
+                    }
+                }
+
+                yield item;
+
+                if timed_out {
+                    break;
+                }
+            }
+
+            usage.succeed();
+            if let Some((id, model)) = &response_ids {
+                telemetry::record_response(&span, id, model, response_usage.as_ref(), &finish_reasons);
+            }
+        }))
     }
 
-    pub async fn models(&self) -> anyhow::Result<ListModelResponse> {
-        Ok(ListModelResponse {
-            data: join_all([
-                self.llm_provider_map
-                    .get(self.secret_manager.clone(), SupportedLlm::Anthropic)
-                    .await?
-                    .models(),
-                self.llm_provider_map
-                    .get(self.secret_manager.clone(), SupportedLlm::AnthropicVertexAi)
-                    .await?
-                    .models(),
-                self.llm_provider_map
-                    .get(self.secret_manager.clone(), SupportedLlm::OpenAi)
-                    .await?
-                    .models(),
-                self.llm_provider_map
-                    .get(self.secret_manager.clone(), SupportedLlm::PerplexityAi)
-                    .await?
-                    .models(),
-            ])
+    /// Sends an embeddings request to a single target, recording its usage.
+    async fn attempt_embeddings(
+        &self,
+        caller: &Caller,
+        target: &ModelTarget,
+        mut request: CreateEmbeddingRequest,
+    ) -> anyhow::Result<CreateEmbeddingResponse> {
+        request.model = target.model.clone();
+
+        let _permits = self
+            .concurrency_limiter
+            .acquire(target, caller.priority)
+            .await?;
+
+        let circuit_breakers = self.circuit_breakers(caller, target);
+        if circuit_breakers.is_some_and(|circuit_breakers| !circuit_breakers.acquire(target)) {
+            return Err(DelegateError::CircuitOpen(target.to_string()).into());
+        }
+
+        let mut usage = self
+            .usage_ledger
+            .start(&caller.key, target.llm, &target.model);
+        let started = Instant::now();
+
+        let span = telemetry::upstream_span("embeddings", target);
+        let timeouts = self.llm_provider_map.timeouts(target.llm);
+        let call = within(timeouts.total(), "response", async {
+            self.provider(caller, target.llm)
+                .await?
+                .embeddings(request)
+                .await
+        });
+        let result = telemetry::with_request_id(caller.request_id.clone(), call)
+            .instrument(span.clone())
+            .await;
+
+        if let Some(circuit_breakers) = circuit_breakers {
+            circuit_breakers.record(target, is_healthy(&result), started.elapsed());
+        }
+
+        match &result {
+            Ok(response) => {
+                usage.set_tokens(response.usage.prompt_tokens, 0);
+                usage.succeed();
+                telemetry::record_embeddings(&span, &response.model, response.usage.prompt_tokens);
+            }
+            Err(e) => {
+                usage.fail();
+                telemetry::record_error(&span, e);
+            }
+        }
+
+        result
+    }
+
+    /// The health of every provider and model called so far.
+    pub fn circuits(&self) -> Vec<CircuitStatus> {
+        self.llm_provider_map.circuit_breakers().status()
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn metrics(&self) -> anyhow::Result<String> {
+        self.metrics.render(&self.circuits())
+    }
+
+    /// The health of the members of every provider pool in use.
+    pub async fn pools(&self) -> Vec<PoolMemberStatus> {
+        self.llm_provider_map.pool_status().await
+    }
+
+    /// The models listed by every provider, skipping those that cannot be reached.
+    async fn listed_models(&self) -> Vec<(SupportedLlm, Model)> {
+        join_all(SupportedLlm::ALL.map(|llm| async move {
+            let provider = self
+                .llm_provider_map
+                .get(self.secret_manager.clone(), llm)
+                .await?;
+            let models = provider.models().await?;
+
+            anyhow::Ok(models.into_iter().map(move |model| (llm, model)))
+        }))
+        .await
+        .into_iter()
+        .filter_map(|r| r.ok())
+        .flatten()
+        .collect()
+    }
+
+    /// Lists the models and aliases the caller may use.
+    pub async fn models(&self, caller: &Caller) -> anyhow::Result<ListModelResponse> {
+        let mut aliases = self
+            .aliases
+            .iter()
+            .chain(caller.config.aliases.iter())
+            .collect::<HashMap<_, _>>();
+        aliases.retain(|alias, _| caller.allows(alias));
+
+        let models = self
+            .listed_models()
             .await
             .into_iter()
-            .filter_map(|r| r.ok())
-            .flatten()
-            .collect(),
+            .map(|(_, model)| model)
+            .filter(|model| caller.allows(&model.id));
+
+        Ok(ListModelResponse {
+            data: models
+                .chain(aliases.into_iter().map(|(alias, route)| Model {
+                    id: alias.clone(),
+                    object: "model".to_string(),
+                    created: 0,
+                    owned_by: route.targets[0].llm.to_string(),
+                }))
+                .collect(),
             ..ListModelResponse::default()
         })
     }
-
-    // pub async fn embeddings(
-    //     &self,
-    //     llm: SupportedLlm,
-    //     request: CreateEmbeddingRequest,
-    // ) -> Result<CreateEmbeddingResponse, OpenAIError> {
-    //     match llm {
-    //         SupportedLlm::OpenAi => Ok(self.openai().await?.embeddings().create(request).await?),
-    //         SupportedLlm::Anthropic => todo!(),
-    //         SupportedLlm::AnthropicVertexAi => todo!(),
-    //     }
-    // }
 }
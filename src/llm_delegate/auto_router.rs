@@ -0,0 +1,341 @@
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use serde::Deserialize;
+
+use crate::{
+    entities::{
+        CompletionRequestMessage, CompletionRequestMessageContentPart,
+        CompletionRequestUserMessageContent, CompletionResponseFormatType, CreateCompletionRequest,
+    },
+    error::ApiError,
+    usage_ledger::known_cost,
+};
+
+use super::{llm_provider::CircuitBreakers, ModelTarget, Route, SupportedLlm};
+
+/// The model name asking the gateway to pick the model itself.
+pub const AUTO_MODEL: &str = "auto";
+
+/// How long the models listed by the providers are reused for.
+const AVAILABLE_TTL: Duration = Duration::from_secs(300);
+
+/// Models tried, in order, for a single automatically routed request.
+const MAX_TARGETS: usize = 3;
+
+/// Completion tokens assumed when estimating the cost of a request without `max_tokens`.
+const DEFAULT_COMPLETION_TOKENS: u32 = 1024;
+
+/// A feature the chosen model must support.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Tools,
+    Vision,
+    Json,
+}
+
+impl TryFrom<&str> for Capability {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "tools" => Ok(Self::Tools),
+            "vision" => Ok(Self::Vision),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow::anyhow!("Unsupported capability")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum Optimize {
+    #[default]
+    Cost,
+    Latency,
+}
+
+/// Constraints on the model picked for an `auto` request, given in the `x-llm-*` headers.
+///
+/// Capabilities the request itself relies on, such as tools or images, are always required.
+#[derive(Clone, Default)]
+pub struct RoutingConstraints {
+    /// `x-llm-max-cost`: the most the request may cost, in USD, counting `max_tokens`
+    /// completion tokens.
+    pub max_cost: Option<f64>,
+    /// `x-llm-max-latency-ms`: the highest acceptable p95 latency of recent calls.
+    pub max_latency: Option<Duration>,
+    /// `x-llm-capabilities`: a comma-separated list of `tools`, `vision` and `json`.
+    pub capabilities: HashSet<Capability>,
+    /// `x-llm-optimize`: either `cost`, the default, or `latency`.
+    pub optimize: Optimize,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RoutingConstraints {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        const CAPABILITIES: &str = "a comma-separated list of tools, vision and json";
+        const OPTIMIZE: &str = "either cost or latency";
+
+        Ok(Self {
+            max_cost: parse_header(parts, "x-llm-max-cost", "a number")?,
+            max_latency: parse_header(
+                parts,
+                "x-llm-max-latency-ms",
+                "a whole number of milliseconds",
+            )?
+            .map(Duration::from_millis),
+            capabilities: header(parts, "x-llm-capabilities", CAPABILITIES)?
+                .into_iter()
+                .flat_map(|value| value.split(','))
+                .map(|capability| {
+                    Capability::try_from(capability.trim())
+                        .map_err(|_| invalid_header("x-llm-capabilities", CAPABILITIES))
+                })
+                .collect::<Result<_, _>>()?,
+            optimize: match header(parts, "x-llm-optimize", OPTIMIZE)? {
+                None | Some("cost") => Optimize::Cost,
+                Some("latency") => Optimize::Latency,
+                Some(_) => return Err(invalid_header("x-llm-optimize", OPTIMIZE)),
+            },
+        })
+    }
+}
+
+/// The value of a routing header, which must be `expected` when set.
+fn header<'a>(parts: &'a Parts, name: &str, expected: &str) -> Result<Option<&'a str>, ApiError> {
+    parts
+        .headers
+        .get(name)
+        .map(|value| value.to_str().map_err(|_| invalid_header(name, expected)))
+        .transpose()
+}
+
+fn parse_header<T: FromStr>(
+    parts: &Parts,
+    name: &str,
+    expected: &str,
+) -> Result<Option<T>, ApiError> {
+    header(parts, name, expected)?
+        .map(|value| value.parse().map_err(|_| invalid_header(name, expected)))
+        .transpose()
+}
+
+fn invalid_header(name: &str, expected: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!("The {name} header must be {expected}."),
+    )
+    .with_code("invalid_routing_constraint")
+}
+
+/// A model the gateway may route `auto` requests to, along with what it supports.
+#[derive(Clone, Deserialize)]
+pub struct CatalogEntry {
+    pub provider: SupportedLlm,
+    /// The model identifier, or a prefix of it when ending with `*`.
+    pub model: String,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+impl CatalogEntry {
+    fn new(provider: SupportedLlm, model: &str, capabilities: &[Capability]) -> Self {
+        Self {
+            provider,
+            model: model.to_string(),
+            capabilities: capabilities.to_vec(),
+        }
+    }
+
+    fn matches(&self, target: &ModelTarget) -> bool {
+        self.provider == target.llm
+            && match self.model.strip_suffix('*') {
+                Some(prefix) => target.model.starts_with(prefix),
+                None => target.model == self.model,
+            }
+    }
+}
+
+/// The catalog used unless one is configured.
+pub fn default_catalog() -> Vec<CatalogEntry> {
+    use Capability::*;
+    use SupportedLlm::*;
+
+    vec![
+        CatalogEntry::new(OpenAi, "gpt-4o", &[Tools, Vision, Json]),
+        CatalogEntry::new(OpenAi, "gpt-4o-mini", &[Tools, Vision, Json]),
+        CatalogEntry::new(OpenAi, "gpt-4-turbo", &[Tools, Vision, Json]),
+        CatalogEntry::new(OpenAi, "gpt-3.5-turbo", &[Tools, Json]),
+        CatalogEntry::new(Anthropic, "claude-3-5-sonnet*", &[Tools, Vision]),
+        CatalogEntry::new(Anthropic, "claude-3-opus*", &[Tools, Vision]),
+        CatalogEntry::new(Anthropic, "claude-3-sonnet*", &[Tools, Vision]),
+        CatalogEntry::new(Anthropic, "claude-3-haiku*", &[Tools, Vision]),
+        CatalogEntry::new(AnthropicVertexAi, "claude-3-5-sonnet*", &[Tools, Vision]),
+        CatalogEntry::new(AnthropicVertexAi, "claude-3-opus*", &[Tools, Vision]),
+        CatalogEntry::new(AnthropicVertexAi, "claude-3-sonnet*", &[Tools, Vision]),
+        CatalogEntry::new(AnthropicVertexAi, "claude-3-haiku*", &[Tools, Vision]),
+        CatalogEntry::new(PerplexityAi, "llama-3.1-sonar-small-128k-online", &[]),
+        CatalogEntry::new(PerplexityAi, "llama-3.1-sonar-large-128k-online", &[]),
+    ]
+}
+
+struct Candidate {
+    target: ModelTarget,
+    cost: Option<f64>,
+    latency: Option<Duration>,
+}
+
+/// Picks the models serving `auto` requests among those in the catalog that the providers
+/// currently list, using their prices and the latency of recent calls.
+pub struct AutoRouter {
+    catalog: Vec<CatalogEntry>,
+    available: Mutex<Option<(Instant, Arc<Vec<ModelTarget>>)>>,
+}
+
+impl AutoRouter {
+    pub fn new(catalog: Vec<CatalogEntry>) -> Self {
+        Self {
+            catalog,
+            available: Mutex::new(None),
+        }
+    }
+
+    /// The models listed by the providers, unless they are too old to be relied upon.
+    pub fn available(&self) -> Option<Arc<Vec<ModelTarget>>> {
+        self.available
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(listed, _)| listed.elapsed() < AVAILABLE_TTL)
+            .map(|(_, available)| available.clone())
+    }
+
+    pub fn set_available(&self, available: Vec<ModelTarget>) -> Arc<Vec<ModelTarget>> {
+        let available = Arc::new(available);
+        *self.available.lock().unwrap() = Some((Instant::now(), available.clone()));
+        available
+    }
+
+    /// Builds the route for a request out of the cheapest, or fastest, eligible models. The
+    /// others make up its fallback chain.
+    pub fn route(
+        &self,
+        available: &[ModelTarget],
+        request: &CreateCompletionRequest,
+        constraints: &RoutingConstraints,
+        circuit_breakers: &CircuitBreakers,
+        allows: impl Fn(&ModelTarget) -> bool,
+    ) -> Option<Route> {
+        let mut capabilities = required_capabilities(request);
+        capabilities.extend(&constraints.capabilities);
+
+        let prompt_tokens = request.estimated_prompt_tokens();
+        let completion_tokens = request.max_tokens.unwrap_or(DEFAULT_COMPLETION_TOKENS);
+
+        let mut candidates = available
+            .iter()
+            .filter(|target| allows(target))
+            .filter(|target| {
+                self.catalog
+                    .iter()
+                    .find(|entry| entry.matches(target))
+                    .is_some_and(|entry| {
+                        capabilities
+                            .iter()
+                            .all(|capability| entry.capabilities.contains(capability))
+                    })
+            })
+            .map(|target| Candidate {
+                target: target.clone(),
+                cost: known_cost(&target.model, prompt_tokens, completion_tokens),
+                latency: circuit_breakers.p95_latency(target),
+            })
+            .filter(|candidate| {
+                constraints
+                    .max_cost
+                    .is_none_or(|max_cost| candidate.cost.is_some_and(|cost| cost <= max_cost))
+            })
+            .filter(|candidate| {
+                constraints.max_latency.is_none_or(|max_latency| {
+                    candidate
+                        .latency
+                        .is_none_or(|latency| latency <= max_latency)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Models without a price, or not called recently, go last.
+        let cost = |candidate: &Candidate| candidate.cost.unwrap_or(f64::INFINITY);
+        let latency = |candidate: &Candidate| candidate.latency.unwrap_or(Duration::MAX);
+        candidates.sort_by(|a, b| match constraints.optimize {
+            Optimize::Cost => cost(a)
+                .total_cmp(&cost(b))
+                .then(latency(a).cmp(&latency(b))),
+            Optimize::Latency => latency(a)
+                .cmp(&latency(b))
+                .then(cost(a).total_cmp(&cost(b))),
+        });
+
+        let targets = candidates
+            .into_iter()
+            .take(MAX_TARGETS)
+            .map(|candidate| candidate.target)
+            .collect::<Vec<_>>();
+
+        (!targets.is_empty()).then(|| Route::from(targets))
+    }
+}
+
+/// The capabilities a request relies on by itself.
+fn required_capabilities(request: &CreateCompletionRequest) -> HashSet<Capability> {
+    let mut capabilities = HashSet::new();
+
+    if request
+        .tools
+        .as_ref()
+        .is_some_and(|tools| !tools.is_empty())
+    {
+        capabilities.insert(Capability::Tools);
+    }
+
+    if request
+        .response_format
+        .as_ref()
+        .is_some_and(|format| matches!(format.kind, CompletionResponseFormatType::JsonObject))
+    {
+        capabilities.insert(Capability::Json);
+    }
+
+    let has_images = request.messages.iter().any(|message| {
+        matches!(
+            message,
+            CompletionRequestMessage::User(message)
+                if matches!(
+                    &message.content,
+                    CompletionRequestUserMessageContent::Array(parts)
+                        if parts.iter().any(|part| matches!(
+                            part,
+                            CompletionRequestMessageContentPart::ImageUrl(_)
+                        ))
+                )
+        )
+    });
+    if has_images {
+        capabilities.insert(Capability::Vision);
+    }
+
+    capabilities
+}
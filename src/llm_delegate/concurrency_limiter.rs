@@ -0,0 +1,392 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{sync::oneshot, time::Instant};
+
+use super::{DelegateError, ModelTarget, SupportedLlm};
+
+/// The class of a request, deciding its place in the queue of a busy provider.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Interactive,
+    #[default]
+    Standard,
+    Batch,
+}
+
+impl TryFrom<&str> for Priority {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "interactive" => Ok(Self::Interactive),
+            "standard" => Ok(Self::Standard),
+            "batch" => Ok(Self::Batch),
+            _ => Err(anyhow::anyhow!("Unsupported priority `{value}`")),
+        }
+    }
+}
+
+/// The most calls in flight to a provider, or to one of its models when `model` is set.
+#[derive(Deserialize)]
+pub struct ConcurrencyLimit {
+    pub provider: SupportedLlm,
+    pub model: Option<String>,
+    pub max_in_flight: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    pub limits: Vec<ConcurrencyLimit>,
+    /// How long, in milliseconds, a request may wait for a slot before being turned down.
+    pub max_queue_wait_ms: u64,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            limits: Vec::new(),
+            max_queue_wait_ms: 10_000,
+        }
+    }
+}
+
+struct Waiter {
+    priority: Priority,
+    /// Arrival order, so that requests of the same class are served first come, first served.
+    sequence: u64,
+    sender: oneshot::Sender<Permit>,
+}
+
+impl Waiter {
+    fn rank(&self) -> (Reverse<Priority>, Reverse<u64>) {
+        (Reverse(self.priority), Reverse(self.sequence))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank() == other.rank()
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
+struct LimitState {
+    in_flight: usize,
+    waiters: BinaryHeap<Waiter>,
+}
+
+struct Limit {
+    max_in_flight: usize,
+    state: Mutex<LimitState>,
+}
+
+impl Limit {
+    async fn acquire(
+        self: &Arc<Self>,
+        priority: Priority,
+        sequence: u64,
+        deadline: Instant,
+    ) -> Option<Permit> {
+        let mut receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight < self.max_in_flight {
+                state.in_flight += 1;
+                return Some(Permit::new(self.clone()));
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push(Waiter {
+                priority,
+                sequence,
+                sender,
+            });
+            receiver
+        };
+
+        match tokio::time::timeout_at(deadline, &mut receiver).await {
+            Ok(permit) => permit.ok(),
+            Err(_) => {
+                // A permit may have been handed over right as the wait ended.
+                receiver.close();
+                receiver.try_recv().ok()
+            }
+        }
+    }
+
+    /// Hands the slot of a finished call over to the next waiter, or frees it.
+    fn release(self: &Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().unwrap();
+                match state.waiters.pop() {
+                    Some(waiter) => waiter,
+                    None => {
+                        state.in_flight -= 1;
+                        return;
+                    }
+                }
+            };
+
+            // Waiters that gave up have dropped their receiver; the permit comes back then.
+            match waiter.sender.send(Permit::new(self.clone())) {
+                Ok(()) => return,
+                Err(mut permit) => permit.limit = None,
+            }
+        }
+    }
+}
+
+/// A slot taken on a limit, released, or handed over to the next waiter, when dropped.
+pub struct Permit {
+    limit: Option<Arc<Limit>>,
+}
+
+impl Permit {
+    fn new(limit: Arc<Limit>) -> Self {
+        Self { limit: Some(limit) }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit.take() {
+            limit.release();
+        }
+    }
+}
+
+/// Caps the calls in flight to each provider and model. Requests over the cap wait for a slot,
+/// by priority class, up to the maximum queue wait.
+pub struct ConcurrencyLimiter {
+    providers: HashMap<SupportedLlm, Arc<Limit>>,
+    models: HashMap<ModelTarget, Arc<Limit>>,
+    max_queue_wait: Duration,
+    sequence: AtomicU64,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        let mut providers = HashMap::new();
+        let mut models = HashMap::new();
+        for limit in config.limits {
+            let state = Arc::new(Limit {
+                max_in_flight: limit.max_in_flight,
+                state: Mutex::new(LimitState {
+                    in_flight: 0,
+                    waiters: BinaryHeap::new(),
+                }),
+            });
+
+            match limit.model {
+                Some(model) => {
+                    models.insert(
+                        ModelTarget {
+                            llm: limit.provider,
+                            model,
+                        },
+                        state,
+                    );
+                }
+                None => {
+                    providers.insert(limit.provider, state);
+                }
+            }
+        }
+
+        Self {
+            providers,
+            models,
+            max_queue_wait: Duration::from_millis(config.max_queue_wait_ms),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a slot on the limits of `target`, the model's first and then the provider's.
+    /// The returned permits must be held for as long as the call is in flight.
+    pub async fn acquire(
+        &self,
+        target: &ModelTarget,
+        priority: Priority,
+    ) -> Result<Vec<Permit>, DelegateError> {
+        let deadline = Instant::now() + self.max_queue_wait;
+        let sequence = self.sequence.fetch_add(1, AtomicOrdering::Relaxed);
+
+        let mut permits = Vec::new();
+        for limit in [self.models.get(target), self.providers.get(&target.llm)]
+            .into_iter()
+            .flatten()
+        {
+            let Some(permit) = limit.acquire(priority, sequence, deadline).await else {
+                return Err(DelegateError::Overloaded(
+                    target.to_string(),
+                    self.max_queue_wait.as_secs().max(1),
+                ));
+            };
+            permits.push(permit);
+        }
+
+        Ok(permits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn limit(max_in_flight: usize) -> Arc<Limit> {
+        Arc::new(Limit {
+            max_in_flight,
+            state: Mutex::new(LimitState {
+                in_flight: 0,
+                waiters: BinaryHeap::new(),
+            }),
+        })
+    }
+
+    fn waiting(limit: &Limit) -> usize {
+        limit.state.lock().unwrap().waiters.len()
+    }
+
+    fn in_flight(limit: &Limit) -> usize {
+        limit.state.lock().unwrap().in_flight
+    }
+
+    fn target() -> ModelTarget {
+        ModelTarget {
+            llm: SupportedLlm::OpenAi,
+            model: "gpt-4o".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_waiters_by_priority_then_in_order_of_arrival() {
+        let limit = limit(1);
+        let held = limit.acquire(Priority::Standard, 0, Instant::now()).await;
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let (sender, mut served) = mpsc::unbounded_channel();
+        let waiters = [
+            (Priority::Batch, 1),
+            (Priority::Standard, 2),
+            (Priority::Interactive, 3),
+            (Priority::Standard, 4),
+        ];
+        for (priority, sequence) in waiters {
+            let limit = limit.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let permit = limit.acquire(priority, sequence, deadline).await;
+                sender.send(sequence).unwrap();
+                drop(permit);
+            });
+        }
+        while waiting(&limit) < waiters.len() {
+            tokio::task::yield_now().await;
+        }
+
+        drop(held);
+        let mut order = Vec::new();
+        for _ in waiters {
+            order.push(served.recv().await.unwrap());
+        }
+
+        assert_eq!(order, [3, 2, 4, 1]);
+        assert_eq!(in_flight(&limit), 0);
+    }
+
+    #[tokio::test]
+    async fn hands_slots_over_past_waiters_that_gave_up() {
+        let limit = limit(1);
+        let held = limit.acquire(Priority::Standard, 0, Instant::now()).await;
+
+        let gave_up = limit
+            .acquire(
+                Priority::Interactive,
+                1,
+                Instant::now() + Duration::from_millis(10),
+            )
+            .await;
+        assert!(gave_up.is_none());
+        assert_eq!(waiting(&limit), 1);
+
+        let waiter = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                limit
+                    .acquire(Priority::Batch, 2, Instant::now() + Duration::from_secs(5))
+                    .await
+            }
+        });
+        while waiting(&limit) < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        drop(held);
+        let permit = waiter.await.unwrap();
+        assert!(permit.is_some());
+        assert_eq!(waiting(&limit), 0);
+        assert_eq!(in_flight(&limit), 1);
+
+        drop(permit);
+        assert_eq!(in_flight(&limit), 0);
+    }
+
+    #[tokio::test]
+    async fn takes_the_model_and_provider_limits_and_turns_down_requests_past_the_wait() {
+        let limiter = ConcurrencyLimiter::new(ConcurrencyConfig {
+            limits: vec![
+                ConcurrencyLimit {
+                    provider: SupportedLlm::OpenAi,
+                    model: Some("gpt-4o".to_string()),
+                    max_in_flight: 1,
+                },
+                ConcurrencyLimit {
+                    provider: SupportedLlm::OpenAi,
+                    model: None,
+                    max_in_flight: 2,
+                },
+            ],
+            max_queue_wait_ms: 10,
+        });
+
+        let permits = limiter
+            .acquire(&target(), Priority::Interactive)
+            .await
+            .unwrap();
+        assert_eq!(permits.len(), 2);
+
+        assert!(matches!(
+            limiter.acquire(&target(), Priority::Interactive).await,
+            Err(DelegateError::Overloaded(..))
+        ));
+
+        drop(permits);
+        assert!(limiter.acquire(&target(), Priority::Batch).await.is_ok());
+    }
+}
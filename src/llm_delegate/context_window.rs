@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::entities::{CompletionRequestMessage, CreateCompletionRequest};
+
+use super::{DelegateError, ModelTarget, Route};
+
+/// How many tokens a model takes in and gives out.
+#[derive(Clone, Copy, Deserialize)]
+pub struct ModelLimits {
+    /// Prompt and completion tokens together.
+    pub context_window: u32,
+    pub max_output: Option<u32>,
+}
+
+impl ModelLimits {
+    const fn new(context_window: u32, max_output: Option<u32>) -> Self {
+        Self {
+            context_window,
+            max_output,
+        }
+    }
+}
+
+/// Known model limits, matched against the model identifier by prefix. More specific prefixes
+/// must come before the shorter ones they share a stem with.
+const MODEL_LIMITS: &[(&str, ModelLimits)] = &[
+    ("gpt-4o-mini", ModelLimits::new(128_000, Some(16_384))),
+    ("gpt-4o", ModelLimits::new(128_000, Some(16_384))),
+    ("gpt-4-turbo", ModelLimits::new(128_000, Some(4_096))),
+    ("gpt-4-32k", ModelLimits::new(32_768, Some(4_096))),
+    ("gpt-4", ModelLimits::new(8_192, Some(4_096))),
+    ("gpt-3.5-turbo", ModelLimits::new(16_385, Some(4_096))),
+    ("claude-3-5-sonnet", ModelLimits::new(200_000, Some(8_192))),
+    ("claude-3-opus", ModelLimits::new(200_000, Some(4_096))),
+    ("claude-3-sonnet", ModelLimits::new(200_000, Some(4_096))),
+    ("claude-3-haiku", ModelLimits::new(200_000, Some(4_096))),
+    ("llama-3.1-sonar", ModelLimits::new(127_072, None)),
+];
+
+/// What to do with a request too large for the model it is routed to.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Turn the request down before it reaches the provider.
+    #[default]
+    Reject,
+    /// Send it to the first target of the route, or else of `reroute_to`, large enough for it.
+    Reroute,
+    /// Drop the oldest messages, other than system ones, until it fits.
+    Truncate,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct OverflowConfig {
+    pub policy: OverflowPolicy,
+    /// Larger-context models requests are rerouted to, in order of preference.
+    pub reroute_to: Vec<ModelTarget>,
+    /// Limits of models missing from, or differing from, the built-in table, by model prefix.
+    pub limits: HashMap<String, ModelLimits>,
+}
+
+/// Checks requests against the context window of the models they are routed to, before
+/// anything is sent upstream.
+///
+/// Prompts are measured with a rough estimate, so limits are only enforced where they are
+/// known.
+pub struct ContextWindows {
+    config: OverflowConfig,
+}
+
+impl ContextWindows {
+    pub fn new(config: OverflowConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn limits(&self, model: &str) -> Option<ModelLimits> {
+        let configured = self
+            .config
+            .limits
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, limits)| *limits);
+
+        configured.or_else(|| {
+            MODEL_LIMITS
+                .iter()
+                .find(|(prefix, _)| model.starts_with(prefix))
+                .map(|(_, limits)| *limits)
+        })
+    }
+
+    /// The tokens the request needs on `target`, and how many it may have, if known.
+    fn measure(
+        &self,
+        target: &ModelTarget,
+        request: &CreateCompletionRequest,
+    ) -> (u32, Option<u32>) {
+        let limits = self.limits(&target.model);
+        let max_tokens = match (request.max_tokens, limits.and_then(|l| l.max_output)) {
+            (Some(max_tokens), Some(max_output)) => max_tokens.min(max_output),
+            (max_tokens, _) => max_tokens.unwrap_or_default(),
+        };
+
+        (
+            request.estimated_prompt_tokens() + max_tokens,
+            limits.map(|limits| limits.context_window),
+        )
+    }
+
+    fn fits(&self, target: &ModelTarget, request: &CreateCompletionRequest) -> bool {
+        let (needed, available) = self.measure(target, request);
+        available.is_none_or(|available| needed <= available)
+    }
+
+    /// Applies the overflow policy to the request and its route. Fallback targets too small
+    /// for the request are dropped whatever the policy, as they would fail anyway.
+    pub fn fit(
+        &self,
+        mut route: Route,
+        request: &mut CreateCompletionRequest,
+        allows: impl Fn(&ModelTarget) -> bool,
+    ) -> Result<Route, DelegateError> {
+        let primary = route.targets[0].clone();
+
+        if !self.fits(&primary, request) {
+            match self.config.policy {
+                OverflowPolicy::Reject => return Err(self.overflow(&primary, request)),
+                OverflowPolicy::Reroute => {
+                    let rerouted = route
+                        .targets
+                        .iter()
+                        .chain(
+                            self.config
+                                .reroute_to
+                                .iter()
+                                .filter(|target| allows(target)),
+                        )
+                        .find(|target| self.fits(target, request))
+                        .cloned()
+                        .ok_or_else(|| self.overflow(&primary, request))?;
+
+                    tracing::info!("request too large for {primary}, rerouted to {rerouted}");
+                    route.targets.retain(|target| *target != rerouted);
+                    route.targets.insert(0, rerouted);
+                }
+                OverflowPolicy::Truncate => {
+                    while !self.fits(&primary, request) {
+                        if !drop_oldest_message(&mut request.messages) {
+                            return Err(self.overflow(&primary, request));
+                        }
+                    }
+
+                    tracing::info!("request too large for {primary}, truncated");
+                }
+            }
+        }
+
+        route.targets.retain(|target| self.fits(target, request));
+
+        Ok(route)
+    }
+
+    fn overflow(&self, target: &ModelTarget, request: &CreateCompletionRequest) -> DelegateError {
+        let (needed, available) = self.measure(target, request);
+        DelegateError::ContextOverflow(target.to_string(), needed, available.unwrap_or_default())
+    }
+}
+
+/// Drops the oldest message that is not a system one, then whatever then leads the
+/// conversation until a user message, such as the tool results and assistant turn answering
+/// it: providers expect conversations to open with the user. The latest message is always
+/// kept.
+fn drop_oldest_message(messages: &mut Vec<CompletionRequestMessage>) -> bool {
+    let Some(oldest) = messages
+        .iter()
+        .position(|message| !matches!(message, CompletionRequestMessage::System(_)))
+    else {
+        return false;
+    };
+    if oldest + 1 >= messages.len() {
+        return false;
+    }
+
+    messages.remove(oldest);
+    while oldest + 1 < messages.len()
+        && !matches!(
+            messages[oldest],
+            CompletionRequestMessage::User(_) | CompletionRequestMessage::System(_)
+        )
+    {
+        messages.remove(oldest);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::SupportedLlm;
+    use super::*;
+
+    fn windows(policy: OverflowPolicy, reroute_to: Vec<ModelTarget>) -> ContextWindows {
+        ContextWindows::new(OverflowConfig {
+            policy,
+            reroute_to,
+            limits: HashMap::from([
+                ("small".to_string(), ModelLimits::new(300, None)),
+                ("large".to_string(), ModelLimits::new(10_000, None)),
+            ]),
+        })
+    }
+
+    fn target(model: &str) -> ModelTarget {
+        ModelTarget {
+            llm: SupportedLlm::OpenAi,
+            model: model.to_string(),
+        }
+    }
+
+    /// A conversation of about 330 tokens, too large for the small model, of which the latest
+    /// message alone fits.
+    fn request() -> CreateCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "small",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "a".repeat(400)},
+                {"role": "assistant", "content": "b".repeat(400)},
+                {"role": "user", "content": "c".repeat(400)},
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn messages(value: serde_json::Value) -> Vec<CompletionRequestMessage> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn roles(messages: &[CompletionRequestMessage]) -> Vec<&'static str> {
+        messages
+            .iter()
+            .map(|message| match message {
+                CompletionRequestMessage::System(_) => "system",
+                CompletionRequestMessage::User(_) => "user",
+                CompletionRequestMessage::Assistant(_) => "assistant",
+                CompletionRequestMessage::Tool(_) => "tool",
+                CompletionRequestMessage::Function(_) => "function",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_requests_too_large_for_the_primary_target() {
+        let windows = windows(OverflowPolicy::Reject, vec![]);
+        let route = Route::from(vec![target("small"), target("large")]);
+
+        let Err(DelegateError::ContextOverflow(model, needed, available)) =
+            windows.fit(route, &mut request(), |_| true)
+        else {
+            panic!("request fitted the small model");
+        };
+        assert_eq!(model, "openai/small");
+        assert!(needed > 300);
+        assert_eq!(available, 300);
+    }
+
+    #[test]
+    fn reroutes_to_the_first_target_large_enough() {
+        let windows = windows(OverflowPolicy::Reroute, vec![target("large")]);
+
+        let route = Route::from(vec![target("small"), target("large-2"), target("large")]);
+        let route = windows.fit(route, &mut request(), |_| true).unwrap();
+        assert!(route.targets == [target("large-2"), target("large")]);
+
+        let route = Route::from(target("small"));
+        let route = windows.fit(route, &mut request(), |_| true).unwrap();
+        assert!(route.targets == [target("large")]);
+
+        let route = Route::from(target("small"));
+        assert!(windows.fit(route, &mut request(), |_| false).is_err());
+    }
+
+    #[test]
+    fn truncates_the_oldest_messages_but_not_system_ones() {
+        let windows = windows(OverflowPolicy::Truncate, vec![]);
+        let mut request = request();
+
+        let route = Route::from(target("small"));
+        let route = windows.fit(route, &mut request, |_| true).unwrap();
+        assert!(route.targets == [target("small")]);
+        assert_eq!(roles(&request.messages), ["system", "user"]);
+
+        let mut request = request.clone();
+        request.max_tokens = Some(300);
+        let route = Route::from(target("small"));
+        assert!(windows.fit(route, &mut request, |_| true).is_err());
+    }
+
+    #[test]
+    fn drops_fallback_targets_too_small_whatever_the_policy() {
+        let windows = windows(OverflowPolicy::Reject, vec![]);
+
+        let route = Route::from(vec![target("large"), target("small"), target("unknown")]);
+        let route = windows.fit(route, &mut request(), |_| true).unwrap();
+        assert!(route.targets == [target("large"), target("unknown")]);
+    }
+
+    #[test]
+    fn drops_the_oldest_turn_up_to_the_next_user_message() {
+        let mut messages = messages(json!([
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "What is the weather?"},
+            {"role": "assistant", "content": "Let me look."},
+            {"role": "tool", "content": "Sunny", "tool_call_id": "call_1"},
+            {"role": "user", "content": "And tomorrow?"},
+            {"role": "assistant", "content": "Rain."},
+            {"role": "user", "content": "Thanks."},
+        ]));
+
+        assert!(drop_oldest_message(&mut messages));
+        assert_eq!(roles(&messages), ["system", "user", "assistant", "user"]);
+
+        assert!(drop_oldest_message(&mut messages));
+        assert_eq!(roles(&messages), ["system", "user"]);
+
+        assert!(!drop_oldest_message(&mut messages));
+        assert_eq!(roles(&messages), ["system", "user"]);
+    }
+}
@@ -0,0 +1,24 @@
+/// Errors raised by the delegate itself, before or instead of reaching a provider.
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum DelegateError {
+    #[error("The model `{0}` does not exist or no provider was specified")]
+    ModelNotFound(String),
+    #[error("The model `{0}` is not available for this key")]
+    ModelNotAllowed(String),
+    #[error("Provider API keys are not enabled for this key")]
+    ByokNotAllowed,
+    #[error("The request was routed to `{0}`, for which no provider API key was supplied")]
+    ByokMissing(String),
+    #[error("The provider `{0}` does not accept API keys")]
+    ByokNotSupported(String),
+    #[error("The circuit for `{0}` is open after repeated upstream failures")]
+    CircuitOpen(String),
+    #[error("Too many requests are waiting for `{0}`, retry after {1}s")]
+    Overloaded(String, u64),
+    #[error("No model satisfies the routing constraints")]
+    NoEligibleModel,
+    #[error("The request needs about {1} tokens but `{0}` has a context window of {2}")]
+    ContextOverflow(String, u32, u32),
+    #[error("The provider `{0}` does not support embeddings")]
+    EmbeddingsNotSupported(String),
+}
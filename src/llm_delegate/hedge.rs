@@ -0,0 +1,44 @@
+use std::{future::Future, time::Duration};
+
+/// What became of the secondary call of a hedge.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Secondary {
+    /// The primary call finished before the delay, so the secondary was never sent.
+    NotStarted,
+    /// Both calls were sent and the result is the primary one's.
+    Lost,
+    /// Both calls were sent and the result is the secondary one's.
+    Won,
+}
+
+/// Runs `primary`, and `secondary` as well if `primary` has not finished within its delay.
+///
+/// The first call to succeed wins and the other one is cancelled by dropping it. When both
+/// fail, the error of the last one is returned.
+pub async fn hedge<T>(
+    primary: impl Future<Output = anyhow::Result<T>>,
+    secondary: Option<(Duration, impl Future<Output = anyhow::Result<T>>)>,
+) -> (Secondary, anyhow::Result<T>) {
+    let Some((delay, secondary)) = secondary else {
+        return (Secondary::NotStarted, primary.await);
+    };
+
+    tokio::pin!(primary);
+    tokio::pin!(secondary);
+
+    tokio::select! {
+        result = &mut primary => return (Secondary::NotStarted, result),
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    tokio::select! {
+        result = &mut primary => match result {
+            Ok(response) => (Secondary::Lost, Ok(response)),
+            Err(_) => (Secondary::Won, secondary.await),
+        },
+        result = &mut secondary => match result {
+            Ok(response) => (Secondary::Won, Ok(response)),
+            Err(_) => (Secondary::Lost, primary.await),
+        },
+    }
+}
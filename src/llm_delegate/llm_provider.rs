@@ -1,22 +1,31 @@
 mod anthropic;
 mod anthropic_vertexai;
+mod chat_completions;
+mod circuit_breaker;
 mod openai;
 mod perplexityai;
+mod provider_pool;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anthropic::Anthropic;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use axum::async_trait;
+pub use circuit_breaker::{CircuitBreakerConfig, CircuitBreakers, CircuitState, CircuitStatus};
 use perplexityai::PerplexityAi;
+use provider_pool::ProviderPool;
+pub use provider_pool::{PoolConfig, PoolMemberStatus};
+use serde::Deserialize;
 use tokio::sync::Mutex;
 
 use crate::{
     entities::{
-        CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse, Model,
+        CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse,
+        CreateEmbeddingRequest, CreateEmbeddingResponse, Model, StreamAggregator,
     },
-    llm_delegate::SupportedLlm,
+    llm_delegate::{SupportedLlm, Timeouts},
     secret_manager::SecretManagerProvider,
+    telemetry,
 };
 
 use anthropic_vertexai::AnthropicVertexAi;
@@ -34,6 +43,11 @@ pub trait AnyLlmProvider: Send + Sync {
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CompletionResponseStream>;
 
+    async fn embeddings(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> anyhow::Result<CreateEmbeddingResponse>;
+
     async fn models(&self) -> anyhow::Result<Vec<Model>>;
 }
 
@@ -41,6 +55,7 @@ pub trait AnyLlmProvider: Send + Sync {
 pub trait LlmProvider: Send + Sync {
     async fn init(
         secret_manager: Arc<dyn SecretManagerProvider>,
+        connect_timeout: Option<Duration>,
     ) -> anyhow::Result<Arc<dyn AnyLlmProvider>>;
 
     async fn completion(
@@ -48,11 +63,22 @@ pub trait LlmProvider: Send + Sync {
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CreateCompletionResponse>;
 
+    /// Chunks the whole response to the request by default, for the providers without a
+    /// streaming API of their own.
     async fn completion_stream(
         &self,
-        _request: CreateCompletionRequest,
+        request: CreateCompletionRequest,
     ) -> anyhow::Result<CompletionResponseStream> {
-        todo!()
+        let (request, include_usage) = request.into_unstreamed();
+        Ok(self.completion(request).await?.into_stream(include_usage))
+    }
+
+    /// Only called for the providers [`supports_embeddings`] lists.
+    async fn embeddings(
+        &self,
+        _request: CreateEmbeddingRequest,
+    ) -> anyhow::Result<CreateEmbeddingResponse> {
+        bail!("embeddings are not supported")
     }
 
     async fn models(&self) -> anyhow::Result<Vec<Model>>;
@@ -77,35 +103,219 @@ where
         Ok(self.completion_stream(request).await?)
     }
 
+    async fn embeddings(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> anyhow::Result<CreateEmbeddingResponse> {
+        Ok(self.embeddings(request).await?)
+    }
+
     async fn models(&self) -> anyhow::Result<Vec<Model>> {
         Ok(self.models().await?)
     }
 }
 
-#[derive(Default)]
-pub struct LlmProviderMap(Mutex<HashMap<SupportedLlm, Arc<dyn AnyLlmProvider>>>);
+/// Disables the retries `async_openai` performs on its own, which are left to the delegate.
+fn no_backoff() -> backoff::ExponentialBackoff {
+    backoff::ExponentialBackoffBuilder::new()
+        .with_max_elapsed_time(Some(std::time::Duration::ZERO))
+        .build()
+}
+
+/// The HTTP client used by the OpenAI-compatible providers.
+fn http_client(connect_timeout: Option<Duration>) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Configuration of the OpenAI-compatible clients that passes the trace context and request ID
+/// of the current call on to the provider.
+#[derive(Clone)]
+struct TracedConfig<C = async_openai::config::OpenAIConfig>(C);
+
+impl<C: async_openai::config::Config> async_openai::config::Config for TracedConfig<C> {
+    fn headers(&self) -> reqwest::header::HeaderMap {
+        let mut headers = self.0.headers();
+        telemetry::inject_headers(&mut headers);
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        self.0.url(path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        self.0.query()
+    }
+
+    fn api_base(&self) -> &str {
+        self.0.api_base()
+    }
+
+    fn api_key(&self) -> &secrecy::Secret<String> {
+        self.0.api_key()
+    }
+}
+
+/// The secret holding the API key of a provider, for those authenticated by one.
+pub fn api_key_secret(llm: SupportedLlm) -> Option<&'static str> {
+    match llm {
+        SupportedLlm::OpenAi => Some("OPENAI_API_KEY"),
+        SupportedLlm::Anthropic => Some("ANTHROPIC_API_KEY"),
+        SupportedLlm::AnthropicVertexAi => None,
+        SupportedLlm::PerplexityAi => Some("PERPLEXITYAI_API_KEY"),
+    }
+}
+
+/// Whether a provider serves embeddings.
+pub fn supports_embeddings(llm: SupportedLlm) -> bool {
+    match llm {
+        SupportedLlm::OpenAi => true,
+        SupportedLlm::Anthropic | SupportedLlm::AnthropicVertexAi | SupportedLlm::PerplexityAi => {
+            false
+        }
+    }
+}
+
+/// Whether the calls to a provider are streamed, whichever the caller asked for, for the
+/// endpoints that only support one of the two.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamingMode {
+    /// As the caller asked.
+    #[default]
+    Native,
+    /// Always, aggregating the stream for the callers asking for a whole response.
+    Always,
+    /// Never, chunking the whole response for the callers asking for a stream.
+    Never,
+}
+
+/// Calls `provider` for a whole response, streaming it if `mode` says so.
+pub async fn completion(
+    provider: &dyn AnyLlmProvider,
+    mode: StreamingMode,
+    request: CreateCompletionRequest,
+) -> anyhow::Result<CreateCompletionResponse> {
+    match mode {
+        StreamingMode::Always => {
+            let stream = provider.completion_stream(request.into_streamed()).await?;
+            StreamAggregator::collect(stream).await
+        }
+        StreamingMode::Native | StreamingMode::Never => provider.completion(request).await,
+    }
+}
+
+/// Calls `provider` for a stream, chunking a whole response if `mode` says so.
+pub async fn completion_stream(
+    provider: &dyn AnyLlmProvider,
+    mode: StreamingMode,
+    request: CreateCompletionRequest,
+) -> anyhow::Result<CompletionResponseStream> {
+    match mode {
+        StreamingMode::Never => {
+            let (request, include_usage) = request.into_unstreamed();
+            Ok(provider
+                .completion(request)
+                .await?
+                .into_stream(include_usage))
+        }
+        StreamingMode::Native | StreamingMode::Always => provider.completion_stream(request).await,
+    }
+}
+
+/// The cached provider clients, along with the health of every provider and model.
+///
+/// Providers with a pool configured are served by it instead of a single client.
+pub struct LlmProviderMap {
+    providers: Mutex<HashMap<SupportedLlm, Arc<dyn AnyLlmProvider>>>,
+    pool_configs: HashMap<SupportedLlm, PoolConfig>,
+    pools: Mutex<Vec<Arc<ProviderPool>>>,
+    circuit_breakers: CircuitBreakers,
+    timeouts: HashMap<SupportedLlm, Timeouts>,
+    streaming: HashMap<SupportedLlm, StreamingMode>,
+}
 
 impl LlmProviderMap {
+    pub fn new(
+        circuit_breaker_config: CircuitBreakerConfig,
+        pool_configs: HashMap<SupportedLlm, PoolConfig>,
+        timeouts: HashMap<SupportedLlm, Timeouts>,
+        streaming: HashMap<SupportedLlm, StreamingMode>,
+    ) -> Self {
+        Self {
+            providers: Mutex::new(HashMap::new()),
+            pool_configs,
+            pools: Mutex::new(Vec::new()),
+            circuit_breakers: CircuitBreakers::new(circuit_breaker_config),
+            timeouts,
+            streaming,
+        }
+    }
+
+    pub fn timeouts(&self, llm: SupportedLlm) -> Timeouts {
+        self.timeouts.get(&llm).copied().unwrap_or_default()
+    }
+
+    pub fn streaming(&self, llm: SupportedLlm) -> StreamingMode {
+        self.streaming.get(&llm).copied().unwrap_or_default()
+    }
+
+    pub fn circuit_breakers(&self) -> &CircuitBreakers {
+        &self.circuit_breakers
+    }
+
     pub async fn get(
         &self,
         secret_manager: Arc<dyn SecretManagerProvider>,
         llm: SupportedLlm,
     ) -> Result<Arc<dyn AnyLlmProvider>> {
-        let mut self_guard = self.0.lock().await;
+        let mut self_guard = self.providers.lock().await;
         if !self_guard.contains_key(&llm) {
-            self_guard.insert(
-                llm,
-                match llm {
-                    SupportedLlm::OpenAi => OpenAi::init(secret_manager).await?,
-                    SupportedLlm::Anthropic => Anthropic::init(secret_manager).await?,
-                    SupportedLlm::AnthropicVertexAi => {
-                        AnthropicVertexAi::init(secret_manager).await?
-                    }
-                    SupportedLlm::PerplexityAi => PerplexityAi::init(secret_manager).await?,
-                },
-            );
+            let connect_timeout = self.timeouts(llm).connect();
+            let provider: Arc<dyn AnyLlmProvider> = match self.pool_configs.get(&llm) {
+                Some(config) => {
+                    let pool = Arc::new(
+                        ProviderPool::init(secret_manager, llm, config, connect_timeout).await?,
+                    );
+                    self.pools.lock().await.push(pool.clone());
+                    pool
+                }
+                None => Self::init(secret_manager, llm, connect_timeout).await?,
+            };
+            self_guard.insert(llm, provider);
         }
 
         Ok(self_guard.get(&llm).unwrap().to_owned())
     }
+
+    /// The health of the members of every pool in use.
+    pub async fn pool_status(&self) -> Vec<PoolMemberStatus> {
+        self.pools
+            .lock()
+            .await
+            .iter()
+            .flat_map(|pool| pool.status())
+            .collect()
+    }
+
+    /// Builds a new client for `llm`, bypassing the cache.
+    pub async fn init(
+        secret_manager: Arc<dyn SecretManagerProvider>,
+        llm: SupportedLlm,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Arc<dyn AnyLlmProvider>> {
+        match llm {
+            SupportedLlm::OpenAi => <OpenAi>::init(secret_manager, connect_timeout).await,
+            SupportedLlm::Anthropic => Anthropic::init(secret_manager, connect_timeout).await,
+            SupportedLlm::AnthropicVertexAi => {
+                AnthropicVertexAi::init(secret_manager, connect_timeout).await
+            }
+            SupportedLlm::PerplexityAi => PerplexityAi::init(secret_manager, connect_timeout).await,
+        }
+    }
 }
@@ -1,90 +1,67 @@
-use std::{ops::Deref, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
-use anthropic::{
-    messages::{self, Content, Messages},
-    Model as AnthropicModel,
-};
+use anthropic::Model as AnthropicModel;
 use axum::async_trait;
+use reqwest::header::HeaderMap;
 
 use crate::{
     entities::{
-        Choice, CompletionResponseMessage, CompletionUsage, CreateCompletionRequest,
-        CreateCompletionResponse, Model, Role,
+        CreateCompletionRequest, CreateCompletionResponse, MessagesError, MessagesRequest,
+        MessagesResponse, Model,
     },
-    llm_delegate::SupportedLlm,
+    llm_delegate::{upstream_error::FailedResponse, SupportedLlm},
     secret_manager::SecretManagerProvider,
+    telemetry,
 };
 
 use super::{AnyLlmProvider, LlmProvider};
 
-#[derive(Clone)]
-pub struct Anthropic(Arc<anthropic::Anthropic>);
-
-impl Deref for Anthropic {
-    type Target = Arc<anthropic::Anthropic>;
+const DEFAULT_API_BASE: &str = "https://api.anthropic.com";
+const API_VERSION: &str = "2023-06-01";
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+/// Calls the Messages API directly rather than through the SDK, which has no way to express
+/// prompt cache breakpoints nor to report the cached tokens.
+pub struct Anthropic {
+    client: reqwest::Client,
+    api_key: String,
+    api_base: String,
 }
 
 #[async_trait]
 impl LlmProvider for Anthropic {
     async fn init(
         secret_manager: Arc<dyn SecretManagerProvider>,
+        connect_timeout: Option<Duration>,
     ) -> anyhow::Result<Arc<dyn AnyLlmProvider>> {
-        let api_key = secret_manager.secret("ANTHROPIC_API_KEY").await.unwrap();
+        let api_key = secret_manager.secret("ANTHROPIC_API_KEY").await?;
+        // Lets pool members point at other endpoints, such as a proxy.
+        let api_base = secret_manager
+            .secret("ANTHROPIC_API_BASE")
+            .await
+            .unwrap_or_else(|_| DEFAULT_API_BASE.to_string());
 
-        Ok(Arc::new(Self(Arc::new(
-            anthropic::Anthropic::builder().api_key(api_key).build()?,
-        ))))
+        Ok(Arc::new(Self {
+            client: super::http_client(connect_timeout)?,
+            api_key,
+            api_base,
+        }))
     }
 
     async fn completion(
         &self,
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CreateCompletionResponse> {
-        Ok(self
-            .0
-            .messages(request.into())
-            .await
-            .and_then(|response| match response {
-                messages::CreateMessageResponse::Message(response) => {
-                    Ok(CreateCompletionResponse {
-                        id: response.id,
-                        choices: vec![Choice {
-                            index: 0,
-                            logprobs: None,
-                            finish_reason: None,
-                            message: CompletionResponseMessage {
-                                content: response.content.get(0).map(|c| match c {
-                                    Content::Text { text } => text.clone(),
-                                    _ => unreachable!(),
-                                }),
-                                tool_calls: None,
-                                role: Role::Assistant,
-                            },
-                        }],
-                        created: std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .expect("Time went backwards")
-                            .as_secs() as u32,
-                        model: response.model,
-                        system_fingerprint: None,
-                        object: "chat.completion".into(),
-                        usage: Some(CompletionUsage {
-                            prompt_tokens: response.usage.input_tokens.unwrap_or(0),
-                            completion_tokens: response.usage.output_tokens,
-                            total_tokens: response.usage.input_tokens.unwrap_or(0)
-                                + response.usage.output_tokens,
-                        }),
-                    })
-                }
-                messages::CreateMessageResponse::Error { error } => {
-                    Err(anyhow::anyhow!("{error:?}"))
-                }
-            })
-            .map(|r| r.into())?)
+        messages(
+            self.client
+                .post(format!(
+                    "{}/v1/messages",
+                    self.api_base.trim_end_matches('/')
+                ))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", API_VERSION)
+                .json(&MessagesRequest::from(request)),
+        )
+        .await
     }
 
     async fn models(&self) -> anyhow::Result<Vec<Model>> {
@@ -116,3 +93,25 @@ impl LlmProvider for Anthropic {
         ])
     }
 }
+
+/// Sends a call to the Messages API, on either Anthropic or Vertex AI, along with the trace
+/// context and request ID of the current call.
+pub(super) async fn messages(
+    request: reqwest::RequestBuilder,
+) -> anyhow::Result<CreateCompletionResponse> {
+    let mut headers = HeaderMap::new();
+    telemetry::inject_headers(&mut headers);
+
+    let response = request.headers(headers).send().await?;
+    telemetry::record_upstream_request_id(response.headers());
+
+    // Kept as the source of the error, so that its status tells whether to retry.
+    if let Err(e) = response.error_for_status_ref() {
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+        let failed = FailedResponse::new(&headers, MessagesError::describe(&body));
+        return Err(anyhow::Error::new(e).context(failed));
+    }
+
+    Ok(response.json::<MessagesResponse>().await?.into())
+}
@@ -1,38 +1,35 @@
-use std::{ops::Deref, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
-use anthropic_vertexai::{
-    messages::{self, Content, Messages},
-    Model as AnthropicVertexAiModel,
-};
+use anthropic_vertexai::Model as AnthropicVertexAiModel;
 use axum::async_trait;
 use futures::future::join_all;
+use google_cloud_auth::{project::Config, token::DefaultTokenSourceProvider};
+use google_cloud_token::{TokenSource, TokenSourceProvider};
 
 use crate::{
-    entities::{
-        Choice, CompletionResponseMessage, CompletionUsage, CreateCompletionRequest,
-        CreateCompletionResponse, Model, Role,
-    },
+    entities::{CreateCompletionRequest, CreateCompletionResponse, MessagesRequest, Model},
     llm_delegate::SupportedLlm,
     secret_manager::SecretManagerProvider,
 };
 
-use super::{AnyLlmProvider, LlmProvider};
-
-#[derive(Clone)]
-pub struct AnthropicVertexAi(Arc<anthropic_vertexai::AnthropicVertexAi>);
+use super::{anthropic, AnyLlmProvider, LlmProvider};
 
-impl Deref for AnthropicVertexAi {
-    type Target = Arc<anthropic_vertexai::AnthropicVertexAi>;
+const API_VERSION: &str = "vertex-2023-10-16";
+const SCOPES: &[&str] = &["https://www.googleapis.com/auth/cloud-platform"];
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+/// Calls the Messages API of Vertex AI directly, as for Anthropic, authenticated with the
+/// application default credentials.
+pub struct AnthropicVertexAi {
+    client: reqwest::Client,
+    token_source: Arc<dyn TokenSource>,
+    api_base: String,
 }
 
 #[async_trait]
 impl LlmProvider for AnthropicVertexAi {
     async fn init(
         secret_manager: Arc<dyn SecretManagerProvider>,
+        connect_timeout: Option<Duration>,
     ) -> anyhow::Result<Arc<dyn AnyLlmProvider>> {
         let mut secrets = join_all([
             secret_manager.secret("GCLOUD_PROJECT_ID"),
@@ -42,61 +39,43 @@ impl LlmProvider for AnthropicVertexAi {
 
         let region = secrets.remove(1)?;
         let project = secrets.remove(0)?;
+        // Lets pool members point at other endpoints, such as a proxy.
+        let api_base = secret_manager
+            .secret("VERTEXAI_API_BASE")
+            .await
+            .unwrap_or_else(|_| format!("https://{region}-aiplatform.googleapis.com"));
+
+        let token_source =
+            DefaultTokenSourceProvider::new(Config::default().with_scopes(SCOPES)).await?;
 
-        Ok(Arc::new(Self(Arc::new(
-            anthropic_vertexai::AnthropicVertexAi::builder()
-                .project(project)
-                .region(region)
-                .build()
-                .await?,
-        ))))
+        Ok(Arc::new(Self {
+            client: super::http_client(connect_timeout)?,
+            token_source: token_source.token_source(),
+            api_base: format!(
+                "{}/v1/projects/{project}/locations/{region}/publishers/anthropic/models",
+                api_base.trim_end_matches('/')
+            ),
+        }))
     }
 
     async fn completion(
         &self,
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CreateCompletionResponse> {
-        Ok(self
-            .0
-            .messages(request.into())
+        let token = self
+            .token_source
+            .token()
             .await
-            .and_then(|response| match response {
-                messages::CreateMessageResponse::Message(response) => {
-                    Ok(CreateCompletionResponse {
-                        id: response.id,
-                        choices: vec![Choice {
-                            index: 0,
-                            logprobs: None,
-                            finish_reason: None,
-                            message: CompletionResponseMessage {
-                                content: response.content.get(0).map(|c| match c {
-                                    Content::Text { text } => text.clone(),
-                                    _ => unreachable!(),
-                                }),
-                                tool_calls: None,
-                                role: Role::Assistant,
-                            },
-                        }],
-                        created: std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .expect("Time went backwards")
-                            .as_secs() as u32,
-                        model: response.model,
-                        system_fingerprint: None,
-                        object: "chat.completion".into(),
-                        usage: Some(CompletionUsage {
-                            prompt_tokens: response.usage.input_tokens.unwrap_or(0),
-                            completion_tokens: response.usage.output_tokens,
-                            total_tokens: response.usage.input_tokens.unwrap_or(0)
-                                + response.usage.output_tokens,
-                        }),
-                    })
-                }
-                messages::CreateMessageResponse::Error { error } => {
-                    Err(anyhow::anyhow!("{error:?}"))
-                }
-            })
-            .map(|r| r.into())?)
+            .map_err(|e| anyhow::anyhow!("failed to get a Google Cloud access token: {e}"))?;
+        let (model, request) = MessagesRequest::from(request).into_vertex_ai(API_VERSION);
+
+        anthropic::messages(
+            self.client
+                .post(format!("{}/{model}:rawPredict", self.api_base))
+                .header("authorization", token)
+                .json(&request),
+        )
+        .await
     }
 
     async fn models(&self) -> anyhow::Result<Vec<Model>> {
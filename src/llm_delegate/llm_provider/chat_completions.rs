@@ -0,0 +1,266 @@
+use async_openai::{
+    config::Config,
+    error::{ApiError, OpenAIError},
+    types::{CreateChatCompletionRequest, CreateChatCompletionResponse},
+};
+use eventsource_stream::Eventsource;
+use futures::StreamExt;
+use serde::Deserialize;
+
+use crate::{
+    entities::{CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse},
+    llm_delegate::upstream_error::FailedResponse,
+    telemetry,
+};
+
+use super::TracedConfig;
+
+/// The body of the failed responses of OpenAI-compatible providers.
+#[derive(Deserialize)]
+struct WrappedError {
+    error: ApiError,
+}
+
+/// Calls the Chat Completions API of an OpenAI-compatible provider directly rather than through
+/// the SDK, which does not expose the headers of the responses, such as their `retry-after` or
+/// request ID.
+///
+/// Errors are those of the SDK, so that they are told apart the same way.
+pub struct ChatCompletions<C> {
+    client: reqwest::Client,
+    config: TracedConfig<C>,
+}
+
+impl<C: Config> ChatCompletions<C> {
+    pub fn new(client: reqwest::Client, config: TracedConfig<C>) -> Self {
+        Self { client, config }
+    }
+
+    pub async fn create(
+        &self,
+        request: CreateCompletionRequest,
+    ) -> anyhow::Result<CreateCompletionResponse> {
+        let response = self.send(request.into()).await?;
+
+        Ok(response
+            .json::<CreateChatCompletionResponse>()
+            .await
+            .map_err(OpenAIError::Reqwest)?
+            .into())
+    }
+
+    pub async fn create_stream(
+        &self,
+        request: CreateCompletionRequest,
+    ) -> anyhow::Result<CompletionResponseStream> {
+        let mut events = self
+            .send(request.into())
+            .await?
+            .bytes_stream()
+            .eventsource();
+
+        Ok(Box::pin(async_stream::stream! {
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(event) if event.data == "[DONE]" => break,
+                    Ok(event) => yield serde_json::from_str(&event.data)
+                        .map_err(|e| OpenAIError::JSONDeserialize(e).into()),
+                    Err(e) => {
+                        yield Err(OpenAIError::StreamError(e.to_string()).into());
+                        break;
+                    }
+                }
+            }
+        }))
+    }
+
+    async fn send(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> anyhow::Result<reqwest::Response> {
+        let response = self
+            .client
+            .post(self.config.url("/chat/completions"))
+            .query(&self.config.query())
+            .headers(self.config.headers())
+            .json(&request)
+            .send()
+            .await
+            .map_err(OpenAIError::Reqwest)?;
+        telemetry::record_upstream_request_id(response.headers());
+
+        if let Err(e) = response.error_for_status_ref() {
+            let headers = response.headers().clone();
+            let body = response.text().await.unwrap_or_default();
+            let error = match serde_json::from_str::<WrappedError>(&body) {
+                Ok(wrapped) => OpenAIError::ApiError(wrapped.error),
+                Err(_) => OpenAIError::Reqwest(e),
+            };
+            let failed = FailedResponse::new(&headers, error.to_string());
+            return Err(anyhow::Error::new(error).context(failed));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_openai::config::OpenAIConfig;
+    use axum::{
+        http::{header, StatusCode},
+        routing::post,
+        Router,
+    };
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::llm_delegate::upstream_error::{is_rate_limited, is_retryable, retry_after};
+
+    /// A client of a provider answering every call with the given response.
+    async fn provider(
+        status: StatusCode,
+        headers: Vec<(header::HeaderName, &'static str)>,
+        body: String,
+    ) -> ChatCompletions<OpenAIConfig> {
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(move || async move {
+                let headers = headers
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.to_string()))
+                    .collect::<Vec<_>>();
+                (status, axum::response::AppendHeaders(headers), body)
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_base = format!("http://{}/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        ChatCompletions::new(
+            reqwest::Client::new(),
+            TracedConfig(OpenAIConfig::new().with_api_base(api_base)),
+        )
+    }
+
+    fn request() -> CreateCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [{ "role": "user", "content": "Hello" }],
+        }))
+        .unwrap()
+    }
+
+    fn chunk(content: &str) -> String {
+        json!({
+            "id": "chatcmpl-1",
+            "choices": [{ "index": 0, "delta": { "content": content }, "finish_reason": null }],
+            "created": 1,
+            "model": "gpt-4o",
+            "object": "chat.completion.chunk",
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn api_errors_keep_their_type_and_retry_after() {
+        let body = json!({
+            "error": {
+                "message": "Rate limit reached for requests",
+                "type": "requests",
+                "param": null,
+                "code": "rate_limit_exceeded",
+            }
+        });
+        let chat = provider(
+            StatusCode::TOO_MANY_REQUESTS,
+            vec![(header::RETRY_AFTER, "7")],
+            body.to_string(),
+        )
+        .await;
+
+        let Err(error) = chat.create(request()).await else {
+            panic!("the call succeeded");
+        };
+
+        assert!(matches!(
+            error.downcast_ref::<OpenAIError>(),
+            Some(OpenAIError::ApiError(ApiError { r#type: Some(kind), .. })) if kind == "requests"
+        ));
+        assert!(is_retryable(&error));
+        assert!(is_rate_limited(&error));
+        assert_eq!(retry_after(&error), Some(Duration::from_secs(7)));
+    }
+
+    #[tokio::test]
+    async fn other_failures_keep_their_status() {
+        let chat = provider(
+            StatusCode::SERVICE_UNAVAILABLE,
+            vec![],
+            "upstream unavailable".to_string(),
+        )
+        .await;
+
+        let Err(error) = chat.create(request()).await else {
+            panic!("the call succeeded");
+        };
+
+        assert!(matches!(
+            error.downcast_ref::<OpenAIError>(),
+            Some(OpenAIError::Reqwest(e)) if e.status() == Some(reqwest::StatusCode::SERVICE_UNAVAILABLE)
+        ));
+        assert!(is_retryable(&error));
+        assert!(!is_rate_limited(&error));
+        assert_eq!(retry_after(&error), None);
+    }
+
+    #[tokio::test]
+    async fn streams_events_until_done() {
+        let body = format!(
+            "data: {}\n\ndata: {}\n\ndata: [DONE]\n\ndata: {}\n\n",
+            chunk("Hel"),
+            chunk("lo"),
+            chunk("ignored"),
+        );
+        let chat = provider(
+            StatusCode::OK,
+            vec![(header::CONTENT_TYPE, "text/event-stream")],
+            body,
+        )
+        .await;
+
+        let content = chat
+            .create_stream(request())
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap().choices[0].delta.content.clone().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(content, ["Hel", "lo"]);
+    }
+
+    #[tokio::test]
+    async fn malformed_events_fail_to_decode() {
+        let chat = provider(
+            StatusCode::OK,
+            vec![(header::CONTENT_TYPE, "text/event-stream")],
+            "data: {\"id\":\n\n".to_string(),
+        )
+        .await;
+
+        let mut stream = chat.create_stream(request()).await.unwrap();
+        let Some(Err(error)) = stream.next().await else {
+            panic!("the event was decoded");
+        };
+
+        assert!(matches!(
+            error.downcast_ref::<OpenAIError>(),
+            Some(OpenAIError::JSONDeserialize(_))
+        ));
+        assert!(!is_retryable(&error));
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::llm_delegate::ModelTarget;
+
+/// When circuits open and how they recover.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Length, in seconds, of the rolling window outcomes are tracked over.
+    pub window_secs: u64,
+    /// Calls needed within the window before the error rate is acted upon.
+    pub min_requests: usize,
+    /// Share of failed calls, between 0 and 1, that opens the circuit.
+    pub error_rate: f64,
+    /// How long, in seconds, an open circuit rejects calls before letting a probe through.
+    pub open_secs: u64,
+    /// Calls slower than this, in milliseconds, count as failures.
+    pub slow_call_ms: Option<u64>,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            min_requests: 10,
+            error_rate: 0.5,
+            open_secs: 30,
+            slow_call_ms: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// The health of a single provider and model, as reported by the admin API.
+#[derive(Serialize)]
+pub struct CircuitStatus {
+    #[serde(flatten)]
+    pub target: ModelTarget,
+    pub state: CircuitState,
+    pub requests: usize,
+    pub error_rate: f64,
+    pub avg_latency_ms: f64,
+    pub p95_latency_ms: f64,
+}
+
+struct Outcome {
+    at: Instant,
+    healthy: bool,
+    latency: Duration,
+}
+
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probe_started: Instant },
+}
+
+struct CircuitBreaker {
+    state: State,
+    outcomes: VecDeque<Outcome>,
+}
+
+impl CircuitBreaker {
+    fn new() -> Self {
+        Self {
+            state: State::Closed,
+            outcomes: VecDeque::new(),
+        }
+    }
+
+    fn acquire(&mut self, config: &CircuitBreakerConfig, now: Instant) -> bool {
+        let open_for = Duration::from_secs(config.open_secs);
+        match self.state {
+            State::Closed => true,
+            State::Open { until } if now < until => false,
+            // A probe that never reported back, e.g. because its caller went away, must not
+            // keep the circuit half-open forever.
+            State::HalfOpen { probe_started } if now < probe_started + open_for => false,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                self.state = State::HalfOpen { probe_started: now };
+                true
+            }
+        }
+    }
+
+    fn record(&mut self, config: &CircuitBreakerConfig, outcome: Outcome) {
+        let healthy = outcome.healthy
+            && config
+                .slow_call_ms
+                .is_none_or(|slow| outcome.latency < Duration::from_millis(slow));
+        let now = outcome.at;
+
+        self.outcomes.push_back(Outcome { healthy, ..outcome });
+        self.prune(config, now);
+
+        match self.state {
+            State::HalfOpen { .. } if healthy => {
+                self.state = State::Closed;
+                self.outcomes.clear();
+            }
+            State::HalfOpen { .. } => self.open(config, now),
+            State::Closed
+                if self.outcomes.len() >= config.min_requests
+                    && self.error_rate() >= config.error_rate =>
+            {
+                self.open(config, now)
+            }
+            _ => {}
+        }
+    }
+
+    fn open(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        self.state = State::Open {
+            until: now + Duration::from_secs(config.open_secs),
+        };
+    }
+
+    fn prune(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        let window = Duration::from_secs(config.window_secs);
+        while self
+            .outcomes
+            .front()
+            .is_some_and(|outcome| now.duration_since(outcome.at) > window)
+        {
+            self.outcomes.pop_front();
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+
+        let failures = self.outcomes.iter().filter(|o| !o.healthy).count();
+        failures as f64 / self.outcomes.len() as f64
+    }
+
+    fn status(&mut self, config: &CircuitBreakerConfig, target: ModelTarget) -> CircuitStatus {
+        let now = Instant::now();
+        self.prune(config, now);
+
+        let mut latencies = self
+            .outcomes
+            .iter()
+            .map(|outcome| outcome.latency.as_secs_f64() * 1000.0)
+            .collect::<Vec<_>>();
+        latencies.sort_by(f64::total_cmp);
+
+        CircuitStatus {
+            target,
+            state: match self.state {
+                State::Closed => CircuitState::Closed,
+                State::Open { until } if now < until => CircuitState::Open,
+                State::Open { .. } | State::HalfOpen { .. } => CircuitState::HalfOpen,
+            },
+            requests: self.outcomes.len(),
+            error_rate: self.error_rate(),
+            avg_latency_ms: if latencies.is_empty() {
+                0.0
+            } else {
+                latencies.iter().sum::<f64>() / latencies.len() as f64
+            },
+            p95_latency_ms: p95(&latencies).unwrap_or_default(),
+        }
+    }
+}
+
+/// The 95th percentile of already sorted values.
+fn p95<T: Copy>(sorted: &[T]) -> Option<T> {
+    sorted
+        .get((sorted.len() * 95 / 100).min(sorted.len().saturating_sub(1)))
+        .copied()
+}
+
+/// Circuit breakers for every provider and model that has been called.
+pub struct CircuitBreakers {
+    config: CircuitBreakerConfig,
+    breakers: Mutex<HashMap<ModelTarget, CircuitBreaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a call to `target` may go ahead. While half-open, only a single probe is let
+    /// through until it reports back.
+    pub fn acquire(&self, target: &ModelTarget) -> bool {
+        self.breakers
+            .lock()
+            .unwrap()
+            .entry(target.clone())
+            .or_insert_with(CircuitBreaker::new)
+            .acquire(&self.config, Instant::now())
+    }
+
+    /// Records the outcome of a call to `target`. Only failures on the provider's side should
+    /// be reported as unhealthy.
+    pub fn record(&self, target: &ModelTarget, healthy: bool, latency: Duration) {
+        self.breakers
+            .lock()
+            .unwrap()
+            .entry(target.clone())
+            .or_insert_with(CircuitBreaker::new)
+            .record(
+                &self.config,
+                Outcome {
+                    at: Instant::now(),
+                    healthy,
+                    latency,
+                },
+            );
+    }
+
+    /// The latency under which 95% of the recent calls to `target` completed, if any were made.
+    pub fn p95_latency(&self, target: &ModelTarget) -> Option<Duration> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.get_mut(target)?;
+        breaker.prune(&self.config, Instant::now());
+
+        let mut latencies = breaker
+            .outcomes
+            .iter()
+            .map(|outcome| outcome.latency)
+            .collect::<Vec<_>>();
+        latencies.sort();
+
+        p95(&latencies)
+    }
+
+    pub fn status(&self) -> Vec<CircuitStatus> {
+        self.breakers
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|(target, breaker)| breaker.status(&self.config, target.clone()))
+            .collect()
+    }
+}
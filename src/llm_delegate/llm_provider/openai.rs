@@ -1,62 +1,112 @@
-use std::sync::Arc;
-
-use axum::async_trait;
-use futures::StreamExt;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
     entities::{
         CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse,
-        ListModelResponse, Model,
+        CreateEmbeddingRequest, CreateEmbeddingResponse, ListModelResponse, Model,
     },
     secret_manager::SecretManagerProvider,
 };
+use async_openai::config::{AzureConfig, Config, OpenAIConfig};
+use axum::async_trait;
+
+use super::{chat_completions::ChatCompletions, AnyLlmProvider, LlmProvider, TracedConfig};
+
+/// The API version Azure OpenAI deployments are called with when none is set.
+const DEFAULT_AZURE_API_VERSION: &str = "2024-06-01";
+
+/// A client of OpenAI, or of an Azure OpenAI deployment.
+pub struct OpenAi<C: OpenAiConfig = OpenAIConfig> {
+    client: async_openai::Client<TracedConfig<C>>,
+    chat: ChatCompletions<C>,
+}
+
+pub type AzureOpenAi = OpenAi<AzureConfig>;
 
-use super::{AnyLlmProvider, LlmProvider};
+/// The configurations of the OpenAI clients, read from the secrets.
+#[async_trait]
+pub trait OpenAiConfig: Config + Clone + Send + Sync + Sized + 'static {
+    async fn from_secrets(secret_manager: &dyn SecretManagerProvider) -> anyhow::Result<Self>;
+}
+
+#[async_trait]
+impl OpenAiConfig for OpenAIConfig {
+    async fn from_secrets(secret_manager: &dyn SecretManagerProvider) -> anyhow::Result<Self> {
+        let secret = secret_manager.secret("OPENAI_API_KEY").await?;
 
-pub struct OpenAi(async_openai::Client<async_openai::config::OpenAIConfig>);
+        let mut config = OpenAIConfig::new().with_api_key(secret);
+        // Lets pool members point at other OpenAI-compatible endpoints.
+        if let Ok(api_base) = secret_manager.secret("OPENAI_API_BASE").await {
+            config = config.with_api_base(api_base);
+        }
 
+        Ok(config)
+    }
+}
+
+/// Azure authenticates with an `api-key` header and names the deployment, rather than the
+/// model, in the URL.
 #[async_trait]
-impl LlmProvider for OpenAi {
+impl OpenAiConfig for AzureConfig {
+    async fn from_secrets(secret_manager: &dyn SecretManagerProvider) -> anyhow::Result<Self> {
+        let api_version = secret_manager
+            .secret("AZURE_OPENAI_API_VERSION")
+            .await
+            .unwrap_or_else(|_| DEFAULT_AZURE_API_VERSION.to_string());
+
+        Ok(AzureConfig::new()
+            .with_api_key(secret_manager.secret("AZURE_OPENAI_API_KEY").await?)
+            .with_api_base(secret_manager.secret("AZURE_OPENAI_API_BASE").await?)
+            .with_deployment_id(secret_manager.secret("AZURE_OPENAI_DEPLOYMENT").await?)
+            .with_api_version(api_version))
+    }
+}
+
+#[async_trait]
+impl<C: OpenAiConfig> LlmProvider for OpenAi<C> {
     async fn init(
         secret_manager: Arc<dyn SecretManagerProvider>,
+        connect_timeout: Option<Duration>,
     ) -> anyhow::Result<Arc<dyn AnyLlmProvider>> {
-        let secret = secret_manager.secret("OPENAI_API_KEY").await.unwrap();
+        let config = TracedConfig(C::from_secrets(secret_manager.as_ref()).await?);
+        let http_client = super::http_client(connect_timeout)?;
 
-        Ok(Arc::new(Self(async_openai::Client::with_config(
-            async_openai::config::OpenAIConfig::new().with_api_key(secret),
-        ))))
+        Ok(Arc::new(Self {
+            client: async_openai::Client::with_config(config.clone())
+                .with_http_client(http_client.clone())
+                .with_backoff(super::no_backoff()),
+            chat: ChatCompletions::new(http_client, config),
+        }))
     }
 
     async fn completion(
         &self,
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CreateCompletionResponse> {
-        Ok(self
-            .0
-            .chat()
-            .create(request.into())
-            .await
-            .map(|r| r.into())?)
+        self.chat.create(request).await
     }
 
     async fn completion_stream(
         &self,
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CompletionResponseStream> {
-        let mut s = self.0.chat().create_stream(request.into()).await?;
-
-        Ok(Box::pin(async_stream::stream! {
-            while let Some(item) = s.next().await {
-                match item {
-                    Ok(item) => yield Ok(serde_json::from_value(serde_json::to_value(item).unwrap()).unwrap()),
-                    Err(e) => yield Err(e.into()),
-                }
-            }
-        }))
+        self.chat.create_stream(request).await
+    }
+
+    async fn embeddings(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> anyhow::Result<CreateEmbeddingResponse> {
+        Ok(self
+            .client
+            .embeddings()
+            .create(request.into())
+            .await?
+            .into())
     }
 
     async fn models(&self) -> anyhow::Result<Vec<Model>> {
-        let models = self.0.models().list().await?;
+        let models = self.client.models().list().await?;
         Ok(serde_json::from_value::<ListModelResponse>(serde_json::to_value(models)?)?.data)
     }
 }
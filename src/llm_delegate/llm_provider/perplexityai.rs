@@ -1,7 +1,4 @@
-use std::sync::Arc;
-
-use axum::async_trait;
-use futures::StreamExt;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
     entities::{
@@ -10,22 +7,28 @@ use crate::{
     llm_delegate::SupportedLlm,
     secret_manager::SecretManagerProvider,
 };
+use async_openai::config::OpenAIConfig;
+use axum::async_trait;
 
-use super::{AnyLlmProvider, LlmProvider};
+use super::{chat_completions::ChatCompletions, AnyLlmProvider, LlmProvider, TracedConfig};
 
-pub struct PerplexityAi(async_openai::Client<async_openai::config::OpenAIConfig>);
+pub struct PerplexityAi(ChatCompletions<OpenAIConfig>);
 
 #[async_trait]
 impl LlmProvider for PerplexityAi {
     async fn init(
         secret_manager: Arc<dyn SecretManagerProvider>,
+        connect_timeout: Option<Duration>,
     ) -> anyhow::Result<Arc<dyn AnyLlmProvider>> {
-        let secret = secret_manager.secret("PERPLEXITYAI_API_KEY").await.unwrap();
+        let secret = secret_manager.secret("PERPLEXITYAI_API_KEY").await?;
 
-        Ok(Arc::new(Self(async_openai::Client::with_config(
-            async_openai::config::OpenAIConfig::new()
-                .with_api_key(secret)
-                .with_api_base("https://api.perplexity.ai"),
+        Ok(Arc::new(Self(ChatCompletions::new(
+            super::http_client(connect_timeout)?,
+            TracedConfig(
+                OpenAIConfig::new()
+                    .with_api_key(secret)
+                    .with_api_base("https://api.perplexity.ai"),
+            ),
         ))))
     }
 
@@ -33,28 +36,14 @@ impl LlmProvider for PerplexityAi {
         &self,
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CreateCompletionResponse> {
-        Ok(self
-            .0
-            .chat()
-            .create(request.into())
-            .await
-            .map(|r| r.into())?)
+        self.0.create(request).await
     }
 
     async fn completion_stream(
         &self,
         request: CreateCompletionRequest,
     ) -> anyhow::Result<CompletionResponseStream> {
-        let mut s = self.0.chat().create_stream(request.into()).await?;
-
-        Ok(Box::pin(async_stream::stream! {
-            while let Some(item) = s.next().await {
-                match item {
-                    Ok(item) => yield Ok(serde_json::from_value(serde_json::to_value(item).unwrap()).unwrap()),
-                    Err(e) => yield Err(e.into()),
-                }
-            }
-        }))
+        self.0.create_stream(request).await
     }
 
     async fn models(&self) -> anyhow::Result<Vec<Model>> {
@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entities::{
+        CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse,
+        CreateEmbeddingRequest, CreateEmbeddingResponse, Model,
+    },
+    llm_delegate::{
+        upstream_error::{is_healthy, is_rate_limited, retry_after},
+        SupportedLlm,
+    },
+    secret_manager::{Overlay, SecretManagerProvider},
+};
+
+use super::{openai::AzureOpenAi, AnyLlmProvider, LlmProvider, LlmProviderMap};
+
+/// Consecutive provider-side failures, other than rate limits, that cool a member down.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Weight given to the latest call in a member's latency average.
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// How a pool picks the member serving a call.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolStrategy {
+    /// Spreads calls in proportion to the members' weights.
+    #[default]
+    RoundRobin,
+    /// The member with the fewest calls in flight.
+    LeastInFlight,
+    /// The member with the lowest recent latency. Members not called yet are tried first.
+    LowestLatency,
+}
+
+/// What serves the calls of a pool member.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolMemberKind {
+    /// The provider of the pool itself.
+    #[default]
+    Native,
+    /// An Azure OpenAI deployment, in an OpenAI pool. Its secrets are `AZURE_OPENAI_API_KEY`,
+    /// `AZURE_OPENAI_API_BASE`, `AZURE_OPENAI_DEPLOYMENT` and, optionally,
+    /// `AZURE_OPENAI_API_VERSION`.
+    AzureOpenai,
+}
+
+/// A single set of credentials, or endpoint, in a pool.
+#[derive(Deserialize)]
+pub struct PoolMemberConfig {
+    pub name: String,
+    #[serde(default)]
+    pub kind: PoolMemberKind,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Secrets overriding the gateway's own for this member, e.g. `OPENAI_API_KEY` and
+    /// `OPENAI_API_BASE`.
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Several credentials or deployments serving the same provider.
+#[derive(Deserialize)]
+pub struct PoolConfig {
+    #[serde(default)]
+    pub strategy: PoolStrategy,
+    /// How long, in seconds, a rate-limited or failing member is left out when the provider
+    /// does not say when to try again.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    pub members: Vec<PoolMemberConfig>,
+}
+
+fn default_cooldown_secs() -> u64 {
+    60
+}
+
+/// The health of a single pool member, as reported by the admin API.
+#[derive(Serialize)]
+pub struct PoolMemberStatus {
+    pub provider: SupportedLlm,
+    pub name: String,
+    pub weight: u32,
+    pub in_flight: usize,
+    pub latency_ms: Option<f64>,
+    pub consecutive_failures: u32,
+    pub cooldown_remaining_ms: u64,
+}
+
+#[derive(Default)]
+struct MemberHealth {
+    /// Running weight of the smooth weighted round-robin.
+    current_weight: i64,
+    latency: Option<Duration>,
+    consecutive_failures: u32,
+    cooldown_until: Option<Instant>,
+}
+
+struct Member {
+    name: String,
+    weight: u32,
+    provider: Arc<dyn AnyLlmProvider>,
+    in_flight: AtomicUsize,
+    health: Mutex<MemberHealth>,
+}
+
+impl Member {
+    fn is_cooling_down(&self, now: Instant) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .cooldown_until
+            .is_some_and(|until| now < until)
+    }
+}
+
+/// A call in progress on a pool member, counted as in flight until dropped.
+struct Lease {
+    member: Arc<Member>,
+    started: Instant,
+}
+
+impl Lease {
+    fn new(member: Arc<Member>) -> Self {
+        member.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self {
+            member,
+            started: Instant::now(),
+        }
+    }
+
+    /// Updates the member's health from the outcome of the call.
+    fn finish<T>(&self, result: &anyhow::Result<T>, cooldown: Duration) {
+        let mut health = self.member.health.lock().unwrap();
+
+        match result {
+            Ok(_) => {
+                let latency = self.started.elapsed();
+                health.latency = Some(match health.latency {
+                    Some(average) => {
+                        average.mul_f64(1.0 - LATENCY_SMOOTHING)
+                            + latency.mul_f64(LATENCY_SMOOTHING)
+                    }
+                    None => latency,
+                });
+                health.consecutive_failures = 0;
+            }
+            Err(e) if is_rate_limited(e) => {
+                let cooldown = retry_after(e).unwrap_or(cooldown);
+                tracing::warn!(
+                    "pool member {} rate limited, cooling down for {cooldown:?}",
+                    self.member.name
+                );
+                health.cooldown_until = Some(Instant::now() + cooldown);
+            }
+            _ if !is_healthy(result) => {
+                health.consecutive_failures += 1;
+                if health.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                    tracing::warn!(
+                        "pool member {} keeps failing, cooling down for {cooldown:?}",
+                        self.member.name
+                    );
+                    health.consecutive_failures = 0;
+                    health.cooldown_until = Some(Instant::now() + cooldown);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.member.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spreads the calls to one provider across several credentials or endpoints.
+///
+/// Members that are rate limited, or keep failing, are left out until their cooldown ends.
+/// When every member is cooling down, the one that recovers first is used anyway.
+pub struct ProviderPool {
+    llm: SupportedLlm,
+    strategy: PoolStrategy,
+    cooldown: Duration,
+    members: Vec<Arc<Member>>,
+}
+
+impl ProviderPool {
+    pub async fn init(
+        secret_manager: Arc<dyn SecretManagerProvider>,
+        llm: SupportedLlm,
+        config: &PoolConfig,
+        connect_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        if config.members.is_empty() {
+            anyhow::bail!("the {llm} pool has no members");
+        }
+
+        let mut members = Vec::with_capacity(config.members.len());
+        for member in &config.members {
+            let secrets = Overlay::with_secrets(secret_manager.clone(), member.secrets.clone());
+            let provider = match member.kind {
+                PoolMemberKind::Native => {
+                    LlmProviderMap::init(secrets, llm, connect_timeout).await?
+                }
+                PoolMemberKind::AzureOpenai if llm == SupportedLlm::OpenAi => {
+                    AzureOpenAi::init(secrets, connect_timeout).await?
+                }
+                PoolMemberKind::AzureOpenai => {
+                    anyhow::bail!(
+                        "{}: only OpenAI pools take Azure OpenAI members",
+                        member.name
+                    )
+                }
+            };
+
+            members.push(Arc::new(Member {
+                name: member.name.clone(),
+                weight: member.weight.max(1),
+                provider,
+                in_flight: AtomicUsize::new(0),
+                health: Mutex::default(),
+            }));
+        }
+
+        Ok(Self {
+            llm,
+            strategy: config.strategy,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            members,
+        })
+    }
+
+    fn acquire(&self) -> Lease {
+        let now = Instant::now();
+        let available = self
+            .members
+            .iter()
+            .filter(|member| !member.is_cooling_down(now))
+            .collect::<Vec<_>>();
+
+        let member = if available.is_empty() {
+            self.members
+                .iter()
+                .min_by_key(|member| member.health.lock().unwrap().cooldown_until)
+        } else {
+            match self.strategy {
+                PoolStrategy::RoundRobin => Self::round_robin(&available),
+                PoolStrategy::LeastInFlight => available
+                    .into_iter()
+                    .min_by_key(|member| member.in_flight.load(Ordering::Relaxed)),
+                PoolStrategy::LowestLatency => available
+                    .into_iter()
+                    .min_by_key(|member| member.health.lock().unwrap().latency),
+            }
+        };
+
+        Lease::new(member.expect("pools have at least one member").clone())
+    }
+
+    /// Smooth weighted round-robin, which interleaves members rather than sending them bursts.
+    fn round_robin<'a>(members: &[&'a Arc<Member>]) -> Option<&'a Arc<Member>> {
+        let total = members
+            .iter()
+            .map(|member| member.weight as i64)
+            .sum::<i64>();
+
+        let mut selected: Option<(&Arc<Member>, i64)> = None;
+        for member in members {
+            let mut health = member.health.lock().unwrap();
+            health.current_weight += member.weight as i64;
+            if selected.is_none_or(|(_, weight)| health.current_weight > weight) {
+                selected = Some((member, health.current_weight));
+            }
+        }
+
+        let (member, _) = selected?;
+        member.health.lock().unwrap().current_weight -= total;
+        Some(member)
+    }
+
+    pub fn status(&self) -> Vec<PoolMemberStatus> {
+        let now = Instant::now();
+        self.members
+            .iter()
+            .map(|member| {
+                let health = member.health.lock().unwrap();
+                PoolMemberStatus {
+                    provider: self.llm,
+                    name: member.name.clone(),
+                    weight: member.weight,
+                    in_flight: member.in_flight.load(Ordering::Relaxed),
+                    latency_ms: health.latency.map(|latency| latency.as_secs_f64() * 1000.0),
+                    consecutive_failures: health.consecutive_failures,
+                    cooldown_remaining_ms: health
+                        .cooldown_until
+                        .map(|until| until.saturating_duration_since(now).as_millis() as u64)
+                        .unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl AnyLlmProvider for ProviderPool {
+    async fn completion(
+        &self,
+        request: CreateCompletionRequest,
+    ) -> anyhow::Result<CreateCompletionResponse> {
+        let lease = self.acquire();
+        let result = lease.member.provider.completion(request).await;
+        lease.finish(&result, self.cooldown);
+
+        result
+    }
+
+    /// The member stays in flight until the stream ends, but its latency is only measured up
+    /// to the first chunk.
+    async fn completion_stream(
+        &self,
+        request: CreateCompletionRequest,
+    ) -> anyhow::Result<CompletionResponseStream> {
+        let lease = self.acquire();
+        let result = match lease.member.provider.completion_stream(request).await {
+            Ok(mut stream) => match stream.next().await {
+                Some(Err(e)) => Err(e),
+                first => Ok((first, stream)),
+            },
+            Err(e) => Err(e),
+        };
+        lease.finish(&result, self.cooldown);
+
+        let (first, mut stream) = result?;
+        Ok(Box::pin(async_stream::stream! {
+            let _lease = lease;
+
+            let mut stream = futures::stream::iter(first).chain(&mut stream);
+            while let Some(item) = stream.next().await {
+                yield item;
+            }
+        }))
+    }
+
+    async fn embeddings(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> anyhow::Result<CreateEmbeddingResponse> {
+        let lease = self.acquire();
+        let result = lease.member.provider.embeddings(request).await;
+        lease.finish(&result, self.cooldown);
+
+        result
+    }
+
+    async fn models(&self) -> anyhow::Result<Vec<Model>> {
+        let lease = self.acquire();
+        lease.member.provider.models().await
+    }
+}
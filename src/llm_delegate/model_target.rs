@@ -0,0 +1,19 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::SupportedLlm;
+
+/// A concrete model served by a specific provider.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ModelTarget {
+    #[serde(rename = "provider")]
+    pub llm: SupportedLlm,
+    pub model: String,
+}
+
+impl fmt::Display for ModelTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.llm, self.model)
+    }
+}
@@ -0,0 +1,96 @@
+use serde::Deserialize;
+
+use crate::entities::{
+    CompletionRequestMessage, CompletionRequestMessageContentPart,
+    CompletionRequestUserMessageContent, CreateCompletionRequest, PromptCacheControl,
+};
+
+use super::{ModelTarget, SupportedLlm};
+
+/// Automatic placement of Anthropic prompt cache breakpoints.
+#[derive(Clone, Copy, Deserialize)]
+pub struct PromptCachingConfig {
+    /// Whether breakpoints are placed on the requests to Anthropic, directly or on Vertex AI,
+    /// that set none themselves.
+    #[serde(default)]
+    pub auto: bool,
+    /// The estimated size, in tokens, from which a prefix is worth caching. Anthropic does not
+    /// cache prefixes shorter than 1024 tokens, or 2048 for its Haiku models.
+    #[serde(default = "default_min_tokens")]
+    pub min_tokens: u32,
+}
+
+fn default_min_tokens() -> u32 {
+    1024
+}
+
+impl Default for PromptCachingConfig {
+    fn default() -> Self {
+        Self {
+            auto: false,
+            min_tokens: default_min_tokens(),
+        }
+    }
+}
+
+impl PromptCachingConfig {
+    /// Places breakpoints after the tool definitions and after the system prompt, which
+    /// Anthropic reads in that order, when the prefix they end is large enough.
+    pub fn place_breakpoints(&self, target: &ModelTarget, request: &mut CreateCompletionRequest) {
+        let is_anthropic = matches!(
+            target.llm,
+            SupportedLlm::Anthropic | SupportedLlm::AnthropicVertexAi
+        );
+        if !self.auto || !is_anthropic || has_breakpoints(request) {
+            return;
+        }
+
+        let estimate = |len: usize| len as u32 / 4;
+
+        let mut prefix = 0;
+        if let Some(tools) = &mut request.tools {
+            prefix += estimate(serde_json::to_string(tools).map_or(0, |tools| tools.len()));
+            if let Some(last) = tools.last_mut().filter(|_| prefix >= self.min_tokens) {
+                last.cache_control = Some(PromptCacheControl::ephemeral());
+            }
+        }
+
+        let mut last_system = None;
+        for message in &mut request.messages {
+            if let CompletionRequestMessage::System(system) = message {
+                prefix += estimate(system.content.len());
+                last_system = Some(system);
+            }
+        }
+        if let Some(system) = last_system.filter(|_| prefix >= self.min_tokens) {
+            system.cache_control = Some(PromptCacheControl::ephemeral());
+        }
+    }
+}
+
+fn has_breakpoints(request: &CreateCompletionRequest) -> bool {
+    let in_tools = request
+        .tools
+        .iter()
+        .flatten()
+        .any(|tool| tool.cache_control.is_some());
+
+    in_tools
+        || request.messages.iter().any(|message| match message {
+            CompletionRequestMessage::System(system) => system.cache_control.is_some(),
+            CompletionRequestMessage::Assistant(assistant) => assistant.cache_control.is_some(),
+            CompletionRequestMessage::User(user) => {
+                user.cache_control.is_some()
+                    || matches!(
+                        &user.content,
+                        CompletionRequestUserMessageContent::Array(parts)
+                            if parts.iter().any(|part| matches!(
+                                part,
+                                CompletionRequestMessageContentPart::Text(text)
+                                    if text.cache_control.is_some()
+                            ))
+                    )
+            }
+            _ => false,
+        })
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How transient upstream failures are retried against the same target.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; zero disables retrying.
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled on each following one.
+    pub base_delay: Duration,
+    /// Upper bound for any single wait, including one requested by the provider.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `retry`, or `None` when the request should not be
+    /// retried again.
+    ///
+    /// A delay requested by the provider is honoured as is, unless it exceeds `max_delay`, in
+    /// which case giving up is preferred over holding the caller. Otherwise the exponential
+    /// backoff is jittered so that concurrent callers do not retry in lockstep.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if retry >= self.max_retries {
+            return None;
+        }
+
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => {
+                let backoff = self
+                    .base_delay
+                    .saturating_mul(2u32.saturating_pow(retry))
+                    .min(self.max_delay);
+
+                Some(backoff / 2 + backoff.mul_f64(rand::thread_rng().gen_range(0.0..0.5)))
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{ModelTarget, SupportedLlm};
+
+/// The targets a model or alias is served by, tried in order until one succeeds.
+///
+/// Configured either as a single target, as a list of fallbacks, each with its own model, or
+/// as an object with the list under `targets` along with `hedge_after_ms`, `splits`, `shadow`
+/// and `sticky`.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "RouteConfig")]
+pub struct Route {
+    pub targets: Vec<ModelTarget>,
+    /// How long a target may take to respond before the same request is also sent to the next
+    /// one, the first to succeed winning. Never hedged when unset.
+    pub hedge_after: Option<Duration>,
+    /// Candidate targets given a share of the traffic, ahead of the regular ones.
+    pub splits: Vec<Split>,
+    /// A target every request is mirrored to, whose responses are stored but never returned.
+    pub shadow: Option<ModelTarget>,
+    pub sticky: Sticky,
+}
+
+/// A share of a route's traffic sent to a candidate target.
+#[derive(Clone, Deserialize)]
+pub struct Split {
+    #[serde(flatten)]
+    pub target: ModelTarget,
+    /// The percentage of requests assigned to the target.
+    pub percent: u32,
+}
+
+/// What keeps a client on the same side of a split across requests.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sticky {
+    /// The gateway key the request was made with.
+    #[default]
+    Key,
+    /// The `user` field of the request, or the gateway key when it is missing.
+    User,
+}
+
+impl Route {
+    /// The route with only its targets on `llm`, unless it has none.
+    pub fn only(mut self, llm: SupportedLlm) -> Option<Self> {
+        self.targets.retain(|target| target.llm == llm);
+        self.splits.retain(|split| split.target.llm == llm);
+
+        (!self.targets.is_empty()).then_some(self)
+    }
+
+    /// Puts the split target the client is assigned to, if any, ahead of the others. `name` is
+    /// the requested model or alias, so that clients are assigned independently on each route.
+    ///
+    /// Assignments are hashed with SHA-256, which unlike the standard library's hasher is the
+    /// same across builds and instances of the gateway.
+    pub fn split(mut self, name: &str, client: &str) -> Self {
+        if self.splits.is_empty() {
+            return self;
+        }
+
+        let digest = Sha256::new()
+            .chain_update(name)
+            .chain_update([0])
+            .chain_update(client)
+            .finalize();
+        let bucket = (u64::from_be_bytes(digest[..8].try_into().unwrap()) % 100) as u32;
+
+        let mut threshold = 0;
+        let assigned = self.splits.iter().find(|split| {
+            threshold += split.percent;
+            bucket < threshold
+        });
+
+        if let Some(split) = assigned {
+            let target = split.target.clone();
+            self.targets.retain(|existing| *existing != target);
+            self.targets.insert(0, target);
+        }
+
+        self
+    }
+}
+
+impl From<ModelTarget> for Route {
+    fn from(target: ModelTarget) -> Self {
+        Self::from(vec![target])
+    }
+}
+
+impl From<Vec<ModelTarget>> for Route {
+    fn from(targets: Vec<ModelTarget>) -> Self {
+        Self {
+            targets,
+            hedge_after: None,
+            splits: Vec::new(),
+            shadow: None,
+            sticky: Sticky::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RouteConfig {
+    Target(ModelTarget),
+    Fallbacks(Vec<ModelTarget>),
+    Detailed {
+        targets: Vec<ModelTarget>,
+        hedge_after_ms: Option<u64>,
+        #[serde(default)]
+        splits: Vec<Split>,
+        shadow: Option<ModelTarget>,
+        #[serde(default)]
+        sticky: Sticky,
+    },
+}
+
+impl TryFrom<RouteConfig> for Route {
+    type Error = anyhow::Error;
+
+    fn try_from(config: RouteConfig) -> Result<Self, Self::Error> {
+        let route = match config {
+            RouteConfig::Target(target) => Route::from(target),
+            RouteConfig::Fallbacks(targets) => Route::from(targets),
+            RouteConfig::Detailed {
+                targets,
+                hedge_after_ms,
+                splits,
+                shadow,
+                sticky,
+            } => Route {
+                targets,
+                hedge_after: hedge_after_ms.map(Duration::from_millis),
+                splits,
+                shadow,
+                sticky,
+            },
+        };
+
+        if route.targets.is_empty() {
+            anyhow::bail!("a route needs at least one target");
+        }
+
+        if route.splits.iter().map(|split| split.percent).sum::<u32>() > 100 {
+            anyhow::bail!("the splits of a route add up to more than 100%");
+        }
+
+        Ok(route)
+    }
+}
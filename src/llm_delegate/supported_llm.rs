@@ -1,4 +1,4 @@
-use std::hash::Hash;
+use std::{fmt, hash::Hash};
 
 use axum::{
     async_trait,
@@ -6,6 +6,7 @@ use axum::{
     http::{request::Parts, StatusCode},
 };
 use headers::{Header, HeaderName, HeaderValue};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum SupportedLlm {
@@ -16,6 +17,13 @@ pub enum SupportedLlm {
 }
 
 impl SupportedLlm {
+    pub const ALL: [Self; 4] = [
+        Self::Anthropic,
+        Self::AnthropicVertexAi,
+        Self::OpenAi,
+        Self::PerplexityAi,
+    ];
+
     fn as_str(&self) -> &'static str {
         match self {
             Self::OpenAi => "openai",
@@ -26,9 +34,9 @@ impl SupportedLlm {
     }
 }
 
-impl ToString for SupportedLlm {
-    fn to_string(&self) -> String {
-        self.as_str().to_string()
+impl fmt::Display for SupportedLlm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -52,6 +60,20 @@ impl TryFrom<&str> for SupportedLlm {
     }
 }
 
+impl Serialize for SupportedLlm {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SupportedLlm {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+
+        Self::try_from(raw.as_str()).map_err(serde::de::Error::custom)
+    }
+}
+
 impl Header for SupportedLlm {
     fn name() -> &'static HeaderName {
         static NAME: HeaderName = HeaderName::from_static("x-llm-provider");
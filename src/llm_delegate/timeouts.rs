@@ -0,0 +1,56 @@
+use std::{future::Future, time::Duration};
+
+use serde::Deserialize;
+
+/// Time limits on the calls to a provider, in milliseconds. Limits left unset are not enforced.
+#[derive(Clone, Copy, Default, Deserialize)]
+pub struct Timeouts {
+    /// Establishing a connection to the API of the provider. The access tokens of Vertex AI
+    /// are fetched over connections of their own, which it does not apply to.
+    pub connect_ms: Option<u64>,
+    /// Receiving the first chunk of a stream.
+    pub first_token_ms: Option<u64>,
+    /// The whole call, up to the end of the stream when streaming.
+    pub total_ms: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect_ms.map(Duration::from_millis)
+    }
+
+    pub fn first_token(&self) -> Option<Duration> {
+        self.first_token_ms.map(Duration::from_millis)
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        self.total_ms.map(Duration::from_millis)
+    }
+}
+
+/// A provider call that ran out of time.
+#[derive(Debug, thiserror::Error)]
+#[error("The provider did not send the {stage} within {after:?}")]
+pub struct UpstreamTimeout {
+    pub stage: &'static str,
+    pub after: Duration,
+}
+
+/// Runs `call`, failing with [`UpstreamTimeout`] if it has not finished within `limit`.
+pub async fn within<T>(
+    limit: Option<Duration>,
+    stage: &'static str,
+    call: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let Some(limit) = limit else {
+        return call.await;
+    };
+
+    tokio::time::timeout(limit, call).await.unwrap_or_else(|_| {
+        Err(UpstreamTimeout {
+            stage,
+            after: limit,
+        }
+        .into())
+    })
+}
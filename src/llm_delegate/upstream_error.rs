@@ -0,0 +1,186 @@
+use std::{borrow::Cow, fmt, time::Duration};
+
+use async_openai::error::OpenAIError;
+
+use super::{timeouts::UpstreamTimeout, DelegateError};
+
+/// OpenAI error types reported for rate limits and server-side failures.
+const RETRYABLE_OPENAI_ERRORS: &[&str] = &["server_error", "requests", "tokens"];
+
+/// Whether a failed upstream call may succeed if repeated or sent to another target: server
+/// errors, rate limits and timeouts are, while anything caused by the request itself is not.
+///
+/// The Anthropic providers keep the failed response as the source of their errors, so they are
+/// told apart by status like any other.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    if error.downcast_ref::<DelegateError>().is_some() {
+        return false;
+    }
+
+    if error.is::<UpstreamTimeout>() {
+        return true;
+    }
+
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return is_retryable_reqwest(error);
+    }
+
+    if let Some(error) = error.downcast_ref::<OpenAIError>() {
+        return match error {
+            OpenAIError::Reqwest(error) => is_retryable_reqwest(error),
+            OpenAIError::ApiError(error) => error
+                .r#type
+                .as_deref()
+                .is_some_and(|kind| RETRYABLE_OPENAI_ERRORS.contains(&kind)),
+            OpenAIError::StreamError(_) => true,
+            _ => false,
+        };
+    }
+
+    false
+}
+
+/// Whether the provider turned the call down because a rate limit or quota was hit.
+pub fn is_rate_limited(error: &anyhow::Error) -> bool {
+    let is_too_many_requests =
+        |error: &reqwest::Error| error.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS);
+
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return is_too_many_requests(error);
+    }
+
+    if let Some(error) = error.downcast_ref::<OpenAIError>() {
+        return match error {
+            OpenAIError::Reqwest(error) => is_too_many_requests(error),
+            OpenAIError::ApiError(error) => error
+                .r#type
+                .as_deref()
+                .is_some_and(|kind| kind == "requests" || kind == "tokens"),
+            _ => false,
+        };
+    }
+
+    false
+}
+
+/// Whether a failure on one target should move the request on to the next one: either it is
+/// retryable, or the target was skipped because its circuit is open or it is too busy.
+pub fn should_fall_back(error: &anyhow::Error) -> bool {
+    is_retryable(error)
+        || matches!(
+            error.downcast_ref::<DelegateError>(),
+            Some(DelegateError::CircuitOpen(_) | DelegateError::Overloaded(..))
+        )
+}
+
+/// Whether the outcome of a call says the provider is healthy. Errors caused by the request
+/// itself do not count against it.
+pub fn is_healthy<T>(result: &anyhow::Result<T>) -> bool {
+    result.as_ref().err().is_none_or(|e| !is_retryable(e))
+}
+
+/// The class of a failed upstream call, few enough to be an attribute of its span: the status
+/// of the failed response, the error type OpenAI gave, or how the call failed otherwise.
+pub fn error_type(error: &anyhow::Error) -> Cow<'static, str> {
+    if error.is::<UpstreamTimeout>() {
+        return "timeout".into();
+    }
+
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return reqwest_error_type(error);
+    }
+
+    if let Some(error) = error.downcast_ref::<OpenAIError>() {
+        return match error {
+            OpenAIError::Reqwest(error) => reqwest_error_type(error),
+            OpenAIError::ApiError(error) => {
+                error.r#type.clone().map_or("api_error".into(), Cow::Owned)
+            }
+            OpenAIError::StreamError(_) => "stream".into(),
+            OpenAIError::JSONDeserialize(_) => "decode".into(),
+            _ => "_OTHER".into(),
+        };
+    }
+
+    "_OTHER".into()
+}
+
+fn reqwest_error_type(error: &reqwest::Error) -> Cow<'static, str> {
+    if let Some(status) = error.status() {
+        status.as_str().to_owned().into()
+    } else if error.is_timeout() {
+        "timeout".into()
+    } else if error.is_connect() {
+        "connect".into()
+    } else if error.is_decode() {
+        "decode".into()
+    } else {
+        "_OTHER".into()
+    }
+}
+
+fn is_retryable_reqwest(error: &reqwest::Error) -> bool {
+    error.is_timeout()
+        || error.is_connect()
+        || error.status().is_some_and(|status| {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        })
+}
+
+/// What a failed response said about itself, as the context of the error of the call it
+/// answered.
+#[derive(Debug)]
+pub struct FailedResponse {
+    pub message: String,
+    /// The delay asked for by its `retry-after` header.
+    pub retry_after: Option<Duration>,
+}
+
+impl FailedResponse {
+    pub fn new(headers: &reqwest::header::HeaderMap, message: String) -> Self {
+        Self {
+            message,
+            retry_after: headers
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(Duration::from_secs),
+        }
+    }
+}
+
+impl fmt::Display for FailedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The delay the provider asked for before trying again, if it gave one.
+///
+/// This is the `retry-after` header of the failed response, and otherwise the hint OpenAI puts
+/// in its rate limit messages, e.g. "Please try again in 1.5s" or "in 20ms".
+pub fn retry_after(error: &anyhow::Error) -> Option<Duration> {
+    if let Some(retry_after) = error
+        .downcast_ref::<FailedResponse>()
+        .and_then(|response| response.retry_after)
+    {
+        return Some(retry_after);
+    }
+
+    let message = error.to_string();
+    let (_, hint) = message.split_once("try again in ")?;
+
+    let digits = hint
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(hint.len());
+    let (value, unit) = hint.split_at(digits);
+    let value = value.parse::<f64>().ok()?;
+
+    if unit.starts_with("ms") {
+        Some(Duration::from_secs_f64(value / 1000.0))
+    } else if unit.starts_with('s') {
+        Some(Duration::from_secs_f64(value))
+    } else {
+        None
+    }
+}
@@ -1,30 +1,59 @@
+mod admin;
 mod app_state;
+mod audit_log;
 mod auth;
+mod caller;
+mod config;
 mod entities;
+mod error;
 mod llm_delegate;
+mod metrics;
+mod response_cache;
+mod resumable_streams;
 mod secret_manager;
+mod shadow_store;
+mod telemetry;
+mod tls;
+mod usage_ledger;
+mod websocket;
 
 use app_state::AppState;
-use auth::auth_middleware;
+use audit_log::AuditLog;
+use auth::{auth_middleware, priority_middleware, ApiKeys, AuthLockout};
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Path, State},
+    http::{HeaderName, StatusCode},
     middleware,
     response::{
         sse::{Event, Sse},
         IntoResponse, Response,
     },
     routing::{get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
 use axum_extra::TypedHeader;
+use caller::Caller;
 use clap::Parser;
-use entities::CreateCompletionRequest;
-use llm_delegate::{LlmDelegate, SupportedLlm};
-use std::{convert::Infallible, time::Duration};
-use tokio_stream::StreamExt;
-use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use config::GatewayConfig;
+use entities::{CreateCompletionRequest, CreateEmbeddingRequest};
+use error::ApiError;
+use llm_delegate::{
+    default_catalog, DelegateConfig, LlmDelegate, ModelTarget, RetryPolicy, RoutingConstraints,
+    Served, SupportedLlm,
+};
+use metrics::Metrics;
+use response_cache::{CacheControl, CacheStatus, ResponseCache};
+use resumable_streams::{ResumableStreams, Resumed, Resumption};
+use shadow_store::ShadowStore;
+use std::{convert::Infallible, net::SocketAddr, path::PathBuf, time::Duration};
+use telemetry::LogFormat;
+use tls::TlsSettings;
+use tokio_stream::{Stream, StreamExt};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use usage_ledger::UsageLedger;
 
 #[derive(Parser)]
 struct Cli {
@@ -37,79 +66,314 @@ struct Cli {
     /// The port to bind to
     #[clap(short, long, default_value = "3000")]
     port: u16,
+    /// The SQLite database where usage records are persisted
+    #[clap(long, env = "USAGE_DB", default_value = "usage.db")]
+    usage_db: PathBuf,
+    /// The SQLite database where responses to shadow traffic are stored
+    #[clap(long, env = "SHADOW_DB", default_value = "shadow.db")]
+    shadow_db: PathBuf,
+    /// A JSON file with model aliases and additional gateway keys
+    #[clap(short, long, env = "GATEWAY_CONFIG")]
+    config: Option<PathBuf>,
+    /// Failed authentication attempts from one address before it is locked out
+    #[clap(long, env = "AUTH_LOCKOUT_THRESHOLD")]
+    auth_lockout_threshold: Option<u32>,
+    /// How long, in seconds, failed authentication attempts are remembered
+    #[clap(long, env = "AUTH_LOCKOUT_WINDOW", default_value = "300")]
+    auth_lockout_window: u64,
+    /// A PEM certificate chain; when set together with the key, the server terminates TLS
+    #[clap(long, env = "TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM private key for the TLS certificate
+    #[clap(long, env = "TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// A PEM CA bundle; when set, clients must present a certificate signed by it
+    #[clap(long, env = "TLS_CLIENT_CA", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// How many times a transient upstream failure is retried against the same target
+    #[clap(long, env = "RETRY_ATTEMPTS", default_value = "2")]
+    retry_attempts: u32,
+    /// The backoff, in milliseconds, before the first retry; doubled on each following one
+    #[clap(long, env = "RETRY_BASE_DELAY_MS", default_value = "250")]
+    retry_base_delay_ms: u64,
+    /// The longest, in milliseconds, to wait before a retry
+    #[clap(long, env = "RETRY_MAX_DELAY_MS", default_value = "10000")]
+    retry_max_delay_ms: u64,
+    /// The format of the logs written to standard output
+    #[clap(long, env = "LOG_FORMAT", value_enum, default_value_t)]
+    log_format: LogFormat,
+    /// Base URL of an OTLP/HTTP collector to export spans to, such as `http://localhost:4318`
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// Label the call metrics with the gateway key that made them, one series per key
+    #[clap(long, env = "METRICS_KEY_LABEL")]
+    metrics_key_label: bool,
 }
 
 impl Cli {
+    fn tls(&self) -> Option<TlsSettings> {
+        Some(TlsSettings {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            client_ca: self.tls_client_ca.clone(),
+        })
+    }
+
     fn app(&self) -> anyhow::Result<Router> {
+        let config = match &self.config {
+            Some(path) => GatewayConfig::load(path)?,
+            None => GatewayConfig::default(),
+        };
+
+        let metrics = Metrics::new(self.metrics_key_label)?;
+        let usage_ledger = UsageLedger::open(&self.usage_db, metrics.clone())?;
         let app_state = AppState::new(
-            LlmDelegate::new(secret_manager::Env::new()),
-            self.token.clone(),
+            LlmDelegate::new(
+                secret_manager::Env::new(),
+                usage_ledger.clone(),
+                metrics.clone(),
+                ShadowStore::open(&self.shadow_db, config.audit.as_ref(), metrics.clone())?,
+                config
+                    .audit
+                    .map(|audit| AuditLog::open(audit, metrics))
+                    .transpose()?,
+                config.cache.map(ResponseCache::open).transpose()?,
+                DelegateConfig {
+                    aliases: config.aliases,
+                    retry_policy: RetryPolicy {
+                        max_retries: self.retry_attempts,
+                        base_delay: Duration::from_millis(self.retry_base_delay_ms),
+                        max_delay: Duration::from_millis(self.retry_max_delay_ms),
+                    },
+                    circuit_breaker: config.circuit_breaker,
+                    pools: config.pools,
+                    catalog: config.catalog.unwrap_or_else(default_catalog),
+                    timeouts: config.timeouts,
+                    streaming: config.streaming,
+                    concurrency: config.concurrency,
+                    overflow: config.overflow,
+                    prompt_caching: config.prompt_caching,
+                },
+            ),
+            usage_ledger,
+            ApiKeys::new(self.token.clone(), config.keys),
+            AuthLockout::new(
+                self.auth_lockout_threshold,
+                Duration::from_secs(self.auth_lockout_window),
+            ),
+            config.resumable_streams.map(ResumableStreams::new),
         );
 
         Ok(Router::new()
             .route("/v1/chat/completions", post(completions))
+            .route("/v1/chat/completions/ws", get(websocket::completions))
+            .route("/v1/chat/completions/streams/:id", get(resume_completion))
             .route("/v1/embeddings", post(embeddings))
             .route("/v1/models", get(models))
+            .route("/metrics", get(admin::metrics))
+            .nest(
+                "/admin",
+                Router::new()
+                    .route("/usage", get(admin::usage))
+                    .route("/circuits", get(admin::circuits))
+                    .route("/pools", get(admin::pools)),
+            )
+            // Runs after authentication, as it adjusts the caller.
+            .layer(middleware::from_fn(priority_middleware))
             .layer(middleware::from_fn_with_state(
                 app_state.clone(),
                 auth_middleware,
             ))
-            .layer(TraceLayer::new_for_http())
+            .layer(TraceLayer::new_for_http().make_span_with(telemetry::request_span))
+            // Outside of the trace layer, so that the request ID is known to its span.
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
             .with_state(app_state))
     }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "llm-gateway=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
     let cli = Cli::parse();
 
+    let tracer_provider = telemetry::init(cli.log_format, cli.otlp_endpoint.as_deref())?;
+
     let app = cli.app()?;
 
-    let listener = tokio::net::TcpListener::bind((cli.host, cli.port)).await?;
+    let listener = tokio::net::TcpListener::bind((cli.host.as_str(), cli.port)).await?;
 
     tracing::debug!("listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    let served = match cli.tls() {
+        Some(tls) => tls::serve(listener, app, tls).await,
+        None => axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .map_err(Into::into),
+    };
+
+    if let Some(tracer_provider) = tracer_provider {
+        tracer_provider.shutdown()?;
+    }
 
-    Ok(())
+    served
 }
 
-async fn models(State(llm_delegate): State<LlmDelegate>) -> Response {
-    Json(llm_delegate.models().await.unwrap()).into_response()
+async fn models(
+    State(llm_delegate): State<LlmDelegate>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Response, ApiError> {
+    Ok(Json(llm_delegate.models(&caller).await?).into_response())
 }
 
-async fn embeddings() -> Response {
-    (StatusCode::NOT_IMPLEMENTED, "".to_string()).into_response()
+async fn embeddings(
+    State(llm_delegate): State<LlmDelegate>,
+    Extension(caller): Extension<Caller>,
+    llm: Option<TypedHeader<SupportedLlm>>,
+    Json(request): Json<CreateEmbeddingRequest>,
+) -> Result<Response, ApiError> {
+    let Served {
+        target, response, ..
+    } = llm_delegate
+        .embeddings(&caller, llm.map(|TypedHeader(llm)| llm), request)
+        .await?;
+
+    Ok((served_by(&target), Json(response)).into_response())
 }
 
 async fn completions(
     State(llm_delegate): State<LlmDelegate>,
-    TypedHeader(llm): TypedHeader<SupportedLlm>,
+    Extension(caller): Extension<Caller>,
+    llm: Option<TypedHeader<SupportedLlm>>,
+    constraints: RoutingConstraints,
+    cache_control: CacheControl,
+    Resumption {
+        streams: resumable_streams,
+        last_event_id,
+    }: Resumption,
     Json(request): Json<CreateCompletionRequest>,
-) -> Response {
+) -> Result<Response, ApiError> {
+    let llm = llm.map(|TypedHeader(llm)| llm);
+
     if request.stream.is_some_and(|f| f) {
-        let stream = llm_delegate.completion_stream(llm, request).await.unwrap();
-        let stream = stream.map(|item| {
-            Ok::<Event, Infallible>(
-                Event::default().data(&serde_json::to_string(&item.unwrap()).unwrap()),
-            )
+        if let Some((resumable_streams, last_event_id)) =
+            resumable_streams.as_ref().zip(last_event_id)
+        {
+            return resumed(resumable_streams.resume(&caller, &last_event_id));
+        }
+
+        let Served {
+            target,
+            response: stream,
+            cache,
+        } = llm_delegate
+            .completion_stream(&caller, llm, &constraints, cache_control, request)
+            .await?;
+        let data = stream.map(|item| match item {
+            Ok(chunk) => serde_json::to_string(&chunk).unwrap(),
+            // The status line is long gone, so failures midway are reported in the stream.
+            Err(e) => serde_json::json!({
+                "error": { "message": e.to_string(), "type": "api_error" }
+            })
+            .to_string(),
         });
 
-        Sse::new(stream)
-            .keep_alive(
-                axum::response::sse::KeepAlive::new()
-                    .interval(Duration::from_secs(1))
-                    .text("keep-alive-text"),
-            )
-            .into_response()
+        Ok(match resumable_streams {
+            Some(resumable_streams) => {
+                let started = resumable_streams.start(&caller, &target, cache, data)?;
+                let events = started
+                    .events
+                    .map(|(id, data)| Event::default().id(id).data(data));
+                sse(&target, cache, Some(&started.id), events)
+            }
+            None => sse(
+                &target,
+                cache,
+                None,
+                data.map(|data| Event::default().data(data)),
+            ),
+        })
     } else {
-        Json(llm_delegate.completion(llm, request).await.unwrap()).into_response()
+        let Served {
+            target,
+            response,
+            cache,
+        } = llm_delegate
+            .completion(&caller, llm, &constraints, cache_control, request)
+            .await?;
+
+        Ok((served_by(&target), cache_status(cache), Json(response)).into_response())
     }
 }
+
+/// Picks up a streamed completion without its request, from the event following the
+/// `Last-Event-ID`, or from its start without one.
+async fn resume_completion(
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Resumption {
+        streams: resumable_streams,
+        last_event_id,
+    }: Resumption,
+) -> Result<Response, ApiError> {
+    resumed(
+        resumable_streams
+            .and_then(|streams| streams.resume_stream(&caller, &id, last_event_id.as_deref())),
+    )
+}
+
+/// Streams the events of a resumed stream, if it could be.
+fn resumed(resumed: Option<Resumed>) -> Result<Response, ApiError> {
+    let resumed = resumed.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "The stream to resume is unknown or has expired.",
+        )
+        .with_code("stream_not_found")
+    })?;
+    let events = resumed
+        .events
+        .map(|(id, data)| Event::default().id(id).data(data));
+
+    Ok(sse(
+        &resumed.target,
+        resumed.cache,
+        Some(&resumed.id),
+        events,
+    ))
+}
+
+/// Streams completion events to the client as server-sent events.
+fn sse(
+    target: &ModelTarget,
+    cache: Option<CacheStatus>,
+    stream_id: Option<&str>,
+    events: impl Stream<Item = Event> + Send + 'static,
+) -> Response {
+    (
+        served_by(target),
+        cache_status(cache),
+        stream_id.map(|id| [(HeaderName::from_static("x-stream-id"), id.to_string())]),
+        Sse::new(events.map(Ok::<Event, Infallible>)).keep_alive(
+            axum::response::sse::KeepAlive::new()
+                .interval(Duration::from_secs(1))
+                .text("keep-alive-text"),
+        ),
+    )
+        .into_response()
+}
+
+/// Reports which provider and model served a completion.
+fn served_by(target: &ModelTarget) -> [(HeaderName, String); 1] {
+    [(
+        HeaderName::from_static("x-llm-served-by"),
+        target.to_string(),
+    )]
+}
+
+/// Reports whether a completion came from the cache, for keys that have caching enabled.
+fn cache_status(cache: Option<CacheStatus>) -> Option<[(HeaderName, &'static str); 1]> {
+    cache.map(|cache| [(HeaderName::from_static("x-cache"), cache.as_str())])
+}
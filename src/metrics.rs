@@ -0,0 +1,238 @@
+use std::time::Duration;
+
+use prometheus::{
+    exponential_buckets, CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{
+    llm_delegate::{CircuitState, CircuitStatus, ModelTarget},
+    usage_ledger::{known_cost, UsageRecord},
+};
+
+/// Prometheus metrics of the upstream calls made by the gateway, served on `/metrics`.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    first_token: HistogramVec,
+    tokens: IntCounterVec,
+    cost: CounterVec,
+    retries: IntCounterVec,
+    fallbacks: IntCounterVec,
+    circuit_state: IntGaugeVec,
+    audit_dropped: IntCounter,
+    usage_dropped: IntCounter,
+    shadow_dropped: IntCounter,
+    key_label: bool,
+}
+
+impl Metrics {
+    /// With `key_label`, the call metrics are also labelled with the gateway key that made them,
+    /// one series per key.
+    pub fn new(key_label: bool) -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("llm_gateway".to_string()), None)?;
+        // From 50ms to about 100s.
+        let buckets = exponential_buckets(0.05, 2.0, 12)?;
+        let labels = |extra: &[&'static str]| {
+            let mut labels = vec!["provider", "model"];
+            if key_label {
+                labels.push("key");
+            }
+            labels.extend_from_slice(extra);
+            labels
+        };
+
+        let requests = IntCounterVec::new(
+            Opts::new("upstream_requests_total", "Calls made to the providers."),
+            &labels(&["status"]),
+        )?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_latency_seconds",
+                "Time taken by the calls to the providers, until the last chunk of streams.",
+            )
+            .buckets(buckets.clone()),
+            &labels(&["status"]),
+        )?;
+        let first_token = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_first_token_seconds",
+                "Time until the first chunk of streamed calls.",
+            )
+            .buckets(buckets),
+            &labels(&[]),
+        )?;
+        let tokens = IntCounterVec::new(
+            Opts::new(
+                "upstream_tokens_total",
+                "Tokens used by the calls to the providers.",
+            ),
+            &labels(&["type"]),
+        )?;
+        let cost = CounterVec::new(
+            Opts::new(
+                "upstream_cost_usd_total",
+                "Cost of the calls to the providers, for models with a known price.",
+            ),
+            &labels(&[]),
+        )?;
+        let retries = IntCounterVec::new(
+            Opts::new("upstream_retries_total", "Calls retried after a failure."),
+            &labels(&[]),
+        )?;
+        let fallbacks = IntCounterVec::new(
+            Opts::new(
+                "upstream_fallbacks_total",
+                "Requests moved on to the next target of their route, by the target that failed.",
+            ),
+            &labels(&[]),
+        )?;
+        let circuit_state = IntGaugeVec::new(
+            Opts::new(
+                "circuit_state",
+                "State of the circuit breakers: 0 closed, 1 half-open, 2 open.",
+            ),
+            &["provider", "model"],
+        )?;
+        let audit_dropped = IntCounter::new(
+            "audit_records_dropped_total",
+            "Audit records dropped because the queue of the audit log was full.",
+        )?;
+        let usage_dropped = IntCounter::new(
+            "usage_records_dropped_total",
+            "Usage records dropped because the queue of the usage ledger was full.",
+        )?;
+        let shadow_dropped = IntCounter::new(
+            "shadow_records_dropped_total",
+            "Shadow records dropped because the queue of the shadow store was full.",
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(first_token.clone()))?;
+        registry.register(Box::new(tokens.clone()))?;
+        registry.register(Box::new(cost.clone()))?;
+        registry.register(Box::new(retries.clone()))?;
+        registry.register(Box::new(fallbacks.clone()))?;
+        registry.register(Box::new(circuit_state.clone()))?;
+        registry.register(Box::new(audit_dropped.clone()))?;
+        registry.register(Box::new(usage_dropped.clone()))?;
+        registry.register(Box::new(shadow_dropped.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            latency,
+            first_token,
+            tokens,
+            cost,
+            retries,
+            fallbacks,
+            circuit_state,
+            audit_dropped,
+            usage_dropped,
+            shadow_dropped,
+            key_label,
+        })
+    }
+
+    /// The label values of a call: its target, then its key when labelled by key, then `extra`.
+    fn values<'a>(
+        &self,
+        llm: &'a str,
+        model: &'a str,
+        key: &'a str,
+        extra: &[&'a str],
+    ) -> Vec<&'a str> {
+        let mut values = vec![llm, model];
+        if self.key_label {
+            values.push(key);
+        }
+        values.extend_from_slice(extra);
+        values
+    }
+
+    /// Counts a finished call, as recorded in the usage ledger.
+    pub fn observe_call(&self, record: &UsageRecord) {
+        let llm = record.llm.to_string();
+        let labels = self.values(&llm, &record.model, &record.key, &[record.status.as_str()]);
+
+        self.requests.with_label_values(&labels).inc();
+        self.latency
+            .with_label_values(&labels)
+            .observe(record.latency.as_secs_f64());
+
+        for (kind, tokens) in [
+            ("prompt", record.prompt_tokens),
+            ("completion", record.completion_tokens),
+        ] {
+            self.tokens
+                .with_label_values(&self.values(&llm, &record.model, &record.key, &[kind]))
+                .inc_by(tokens.into());
+        }
+
+        if let Some(cost) = known_cost(
+            &record.model,
+            record.prompt_tokens,
+            record.completion_tokens,
+        ) {
+            self.cost
+                .with_label_values(&self.values(&llm, &record.model, &record.key, &[]))
+                .inc_by(cost);
+        }
+    }
+
+    pub fn observe_first_token(&self, target: &ModelTarget, key: &str, elapsed: Duration) {
+        let llm = target.llm.to_string();
+        self.first_token
+            .with_label_values(&self.values(&llm, &target.model, key, &[]))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn count_retry(&self, target: &ModelTarget, key: &str) {
+        let llm = target.llm.to_string();
+        self.retries
+            .with_label_values(&self.values(&llm, &target.model, key, &[]))
+            .inc();
+    }
+
+    pub fn count_fallback(&self, target: &ModelTarget, key: &str) {
+        let llm = target.llm.to_string();
+        self.fallbacks
+            .with_label_values(&self.values(&llm, &target.model, key, &[]))
+            .inc();
+    }
+
+    pub fn count_dropped_audit_record(&self) {
+        self.audit_dropped.inc();
+    }
+
+    pub fn count_dropped_usage_record(&self) {
+        self.usage_dropped.inc();
+    }
+
+    pub fn count_dropped_shadow_record(&self) {
+        self.shadow_dropped.inc();
+    }
+
+    /// Renders every metric in the Prometheus text format, with the circuits as they are now.
+    pub fn render(&self, circuits: &[CircuitStatus]) -> anyhow::Result<String> {
+        for circuit in circuits {
+            let state = match circuit.state {
+                CircuitState::Closed => 0,
+                CircuitState::HalfOpen => 1,
+                CircuitState::Open => 2,
+            };
+            self.circuit_state
+                .with_label_values(&[&circuit.target.llm.to_string(), &circuit.target.model])
+                .set(state);
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
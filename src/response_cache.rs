@@ -0,0 +1,414 @@
+mod memory_backend;
+mod semantic_index;
+mod sqlite_backend;
+
+use std::{convert::Infallible, num::NonZeroUsize, path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    caller::Caller,
+    entities::{
+        CompletionResponseStream, CreateCompletionRequest, CreateCompletionResponse,
+        StreamAggregator,
+    },
+    llm_delegate::{ModelTarget, SupportedLlm},
+    usage_ledger::UsageRecord,
+};
+
+use memory_backend::MemoryBackend;
+use semantic_index::{Scope, SemanticIndex};
+use sqlite_backend::SqliteBackend;
+
+/// Where cached responses are kept.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CacheBackendConfig {
+    /// In memory, evicting the least recently used response beyond `capacity`.
+    Memory {
+        #[serde(default = "default_capacity")]
+        capacity: NonZeroUsize,
+    },
+    /// In a SQLite database, which survives restarts.
+    Sqlite { path: PathBuf },
+}
+
+fn default_capacity() -> NonZeroUsize {
+    NonZeroUsize::new(10_000).unwrap()
+}
+
+#[derive(Deserialize)]
+pub struct CacheConfig {
+    pub backend: CacheBackendConfig,
+    /// How long responses are served from the cache.
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Serving responses to paraphrases of prior requests, for the keys opting in. Off when
+    /// omitted.
+    #[serde(default)]
+    pub semantic: Option<SemanticCacheConfig>,
+}
+
+fn default_ttl_secs() -> u64 {
+    24 * 3600
+}
+
+/// Matches requests on the meaning of their last user message rather than on their exact
+/// content. Matches are only made between requests of the same key that are otherwise
+/// identical: same model, parameters and conversation up to that message.
+#[derive(Deserialize)]
+pub struct SemanticCacheConfig {
+    /// The model last user messages are embedded with, through the gateway's own embeddings.
+    #[serde(default = "default_embedding_model")]
+    pub embedding_model: ModelTarget,
+    /// The cosine similarity, up to 1, from which the response to a prior request is served.
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    /// Responses kept for each key and conversation, the oldest being dropped beyond.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_embedding_model() -> ModelTarget {
+    ModelTarget {
+        llm: SupportedLlm::OpenAi,
+        model: "text-embedding-3-small".to_string(),
+    }
+}
+
+fn default_threshold() -> f32 {
+    0.95
+}
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+/// A response kept in the cache, along with the target that produced it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub target: ModelTarget,
+    pub response: CreateCompletionResponse,
+}
+
+/// Whether a response was served from the cache, reported in the `x-cache` header.
+#[derive(Clone, Copy)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hit => "HIT",
+            Self::Miss => "MISS",
+        }
+    }
+}
+
+/// The `cache-control` directives of a request: `no-cache` skips the lookup, `no-store`
+/// keeps the response out of the cache.
+#[derive(Clone, Copy, Default)]
+pub struct CacheControl {
+    pub no_cache: bool,
+    pub no_store: bool,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CacheControl {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let mut cache_control = Self::default();
+        for directive in parts
+            .headers
+            .get_all("cache-control")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            match directive.trim().to_ascii_lowercase().as_str() {
+                "no-cache" => cache_control.no_cache = true,
+                "no-store" => cache_control.no_store = true,
+                _ => {}
+            }
+        }
+
+        Ok(cache_control)
+    }
+}
+
+trait CacheBackend: Send + Sync {
+    /// The response stored under `key`, unless it expired by `now`, in milliseconds since the
+    /// Unix epoch.
+    fn get(&self, key: &str, now: u64) -> anyhow::Result<Option<CachedResponse>>;
+
+    fn put(&self, key: &str, entry: &CachedResponse, expires_at: u64) -> anyhow::Result<()>;
+}
+
+/// Opt-in cache of responses to identical requests, for deterministic prompts sent over and
+/// over such as those of CI and evals, and optionally to similar ones.
+#[derive(Clone)]
+pub struct ResponseCache {
+    backend: Arc<dyn CacheBackend>,
+    ttl: Duration,
+    semantic: Option<Arc<Semantic>>,
+}
+
+struct Semantic {
+    index: SemanticIndex,
+    embedding_model: ModelTarget,
+    threshold: f32,
+}
+
+impl ResponseCache {
+    pub fn open(config: CacheConfig) -> anyhow::Result<Self> {
+        let backend: Arc<dyn CacheBackend> = match config.backend {
+            CacheBackendConfig::Memory { capacity } => Arc::new(MemoryBackend::new(capacity)),
+            CacheBackendConfig::Sqlite { path } => Arc::new(SqliteBackend::open(path)?),
+        };
+
+        Ok(Self {
+            backend,
+            ttl: Duration::from_secs(config.ttl_secs),
+            semantic: config.semantic.map(|config| {
+                Arc::new(Semantic {
+                    index: SemanticIndex::new(config.max_entries),
+                    embedding_model: config.embedding_model,
+                    threshold: config.threshold,
+                })
+            }),
+        })
+    }
+
+    /// The cache entry of a request, if the caller's key has caching enabled. Requests matched
+    /// semantically still need their [`CacheEntry::semantic_input`] embedded before the lookup.
+    pub fn entry(
+        &self,
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        cache_control: CacheControl,
+        request: &CreateCompletionRequest,
+    ) -> Option<CacheEntry> {
+        let canonical = Self::canonical(request)?;
+        let key = caller
+            .config
+            .cache
+            .then(|| Self::hash(caller, llm, &canonical));
+        let semantic = self.semantic.is_some()
+            && caller.config.semantic_cache
+            && !(cache_control.no_cache && cache_control.no_store);
+        let semantic = semantic
+            .then(|| {
+                Some(SemanticEntry {
+                    scope: Self::scope(caller, llm, canonical)?,
+                    input: request.last_user_text()?,
+                    embedding: None,
+                })
+            })
+            .flatten();
+        if key.is_none() && semantic.is_none() {
+            return None;
+        }
+
+        Some(CacheEntry {
+            cache: self.clone(),
+            key,
+            semantic,
+            cache_control,
+        })
+    }
+
+    /// The canonical JSON of a request, without the streaming options so that streamed and
+    /// plain requests share responses.
+    fn canonical(request: &CreateCompletionRequest) -> Option<serde_json::Value> {
+        let mut canonical = serde_json::to_value(request).ok()?;
+        if let Some(canonical) = canonical.as_object_mut() {
+            canonical.remove("stream");
+            canonical.remove("stream_options");
+        }
+
+        Some(canonical)
+    }
+
+    /// The requests a request may be matched with semantically: those whose canonical JSON
+    /// differs only by their last user message.
+    fn scope(
+        caller: &Caller,
+        llm: Option<SupportedLlm>,
+        mut canonical: serde_json::Value,
+    ) -> Option<Scope> {
+        let messages = canonical.get_mut("messages")?.as_array_mut()?;
+        let last_user = messages
+            .iter()
+            .rposition(|message| message["role"] == "user")?;
+        messages.remove(last_user);
+
+        Some(Self::hash(caller, llm, &canonical))
+    }
+
+    /// Requests are keyed on a hash of their canonical JSON along with the provider and the key.
+    /// Keys never share responses, as they may stand for different tenants.
+    fn hash(caller: &Caller, llm: Option<SupportedLlm>, canonical: &serde_json::Value) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(caller.key.as_bytes());
+        hasher.update([0]);
+        hasher.update(llm.map(|llm| llm.to_string()).unwrap_or_default());
+        hasher.update([0]);
+        hasher.update(canonical.to_string());
+
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// The place of a request in the cache, by its exact content, its meaning, or both.
+pub struct CacheEntry {
+    cache: ResponseCache,
+    key: Option<String>,
+    semantic: Option<SemanticEntry>,
+    cache_control: CacheControl,
+}
+
+/// The place of a request in the semantic index, once its last user message is embedded.
+struct SemanticEntry {
+    scope: Scope,
+    input: String,
+    embedding: Option<Vec<f32>>,
+}
+
+impl CacheEntry {
+    /// The last user message of the request and the model to embed it with, if it is matched
+    /// semantically and not embedded yet.
+    pub fn semantic_input(&self) -> Option<(&ModelTarget, &str)> {
+        let semantic = self.cache.semantic.as_ref()?;
+        let entry = self.semantic.as_ref()?;
+        if entry.embedding.is_some() {
+            return None;
+        }
+
+        Some((&semantic.embedding_model, &entry.input))
+    }
+
+    pub fn set_embedding(&mut self, embedding: Vec<f32>) {
+        if let Some(entry) = &mut self.semantic {
+            entry.embedding = Some(embedding);
+        }
+    }
+
+    /// The scope and embedding of the request in the semantic index, once embedded.
+    fn semantic(&self) -> Option<(&Scope, &[f32])> {
+        let entry = self.semantic.as_ref()?;
+        Some((&entry.scope, entry.embedding.as_deref()?))
+    }
+
+    /// The cached response, unless the request asked for a fresh one. An exact match is
+    /// preferred to a semantic one. Failing to read the cache is logged and treated as a miss.
+    pub async fn lookup(&self) -> Option<CachedResponse> {
+        if self.cache_control.no_cache {
+            return None;
+        }
+
+        if let Some(cached) = self.lookup_exact().await {
+            return Some(cached);
+        }
+
+        let semantic = self.cache.semantic.as_ref()?;
+        let (scope, embedding) = self.semantic()?;
+        let (similarity, cached) = semantic
+            .index
+            .nearest(scope, embedding, UsageRecord::now())?;
+        if similarity < semantic.threshold {
+            tracing::debug!(similarity, "no semantic cache match");
+            return None;
+        }
+
+        tracing::info!(similarity, target = %cached.target, "semantic cache hit");
+        Some(cached)
+    }
+
+    async fn lookup_exact(&self) -> Option<CachedResponse> {
+        let backend = self.cache.backend.clone();
+        let key = self.key.clone()?;
+        let result =
+            tokio::task::spawn_blocking(move || backend.get(&key, UsageRecord::now())).await;
+
+        match result {
+            Ok(Ok(cached)) => cached,
+            Ok(Err(e)) => {
+                tracing::error!("failed to read response cache: {e}");
+                None
+            }
+            Err(e) => {
+                tracing::error!("response cache reader task failed: {e}");
+                None
+            }
+        }
+    }
+
+    /// Stores a response in the background, unless the request asked for it not to be.
+    pub fn store(&self, target: &ModelTarget, response: &CreateCompletionResponse) {
+        if self.cache_control.no_store {
+            return;
+        }
+
+        let entry = CachedResponse {
+            target: target.clone(),
+            response: response.clone(),
+        };
+        let expires_at = UsageRecord::now() + self.cache.ttl.as_millis() as u64;
+
+        if let Some((semantic, (scope, embedding))) =
+            self.cache.semantic.as_ref().zip(self.semantic())
+        {
+            semantic
+                .index
+                .insert(scope.clone(), embedding.to_vec(), entry.clone(), expires_at);
+        }
+
+        let backend = self.cache.backend.clone();
+        let Some(key) = self.key.clone() else {
+            return;
+        };
+        tokio::spawn(async move {
+            let result =
+                tokio::task::spawn_blocking(move || backend.put(&key, &entry, expires_at)).await;
+
+            match result {
+                Ok(Err(e)) => tracing::error!("failed to write response cache: {e}"),
+                Err(e) => tracing::error!("response cache writer task failed: {e}"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+
+    /// Passes a stream through, storing the response reassembled from its chunks once it ends.
+    /// Streams that fail or are cancelled midway are not stored.
+    pub fn stream(
+        self,
+        target: &ModelTarget,
+        mut stream: CompletionResponseStream,
+    ) -> CompletionResponseStream {
+        let target = target.clone();
+
+        Box::pin(async_stream::stream! {
+            let mut aggregator = StreamAggregator::new();
+            let mut failed = false;
+            while let Some(item) = stream.next().await {
+                match &item {
+                    Ok(chunk) => aggregator.push(chunk),
+                    Err(_) => failed = true,
+                }
+
+                yield item;
+            }
+
+            if !failed {
+                self.store(&target, &aggregator.finish());
+            }
+        })
+    }
+}
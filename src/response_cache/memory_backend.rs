@@ -0,0 +1,41 @@
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use lru::LruCache;
+
+use super::{CacheBackend, CachedResponse};
+
+/// Keeps responses in memory, evicting the least recently used ones beyond its capacity.
+pub struct MemoryBackend {
+    entries: Mutex<LruCache<String, (u64, CachedResponse)>>,
+}
+
+impl MemoryBackend {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn get(&self, key: &str, now: u64) -> anyhow::Result<Option<CachedResponse>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((expires_at, entry)) if *expires_at > now => Ok(Some(entry.clone())),
+            Some(_) => {
+                entries.pop(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &str, entry: &CachedResponse, expires_at: u64) -> anyhow::Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .put(key.to_string(), (expires_at, entry.clone()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use super::CachedResponse;
+
+/// A hash of the key and of everything in a request but its last user message, within which
+/// responses may be matched.
+pub type Scope = String;
+
+struct Indexed {
+    /// Normalised, so that the dot product of two embeddings is their cosine similarity.
+    embedding: Vec<f32>,
+    expires_at: u64,
+    entry: CachedResponse,
+}
+
+/// In-memory index of responses by the embedding of their prompt, searched exhaustively.
+///
+/// Each scope keeps its most recent `max_entries` responses.
+pub struct SemanticIndex {
+    scopes: Mutex<HashMap<Scope, VecDeque<Indexed>>>,
+    max_entries: usize,
+}
+
+impl SemanticIndex {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            scopes: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    /// The response in `scope` whose embedding is the most similar to `embedding`, along with
+    /// their cosine similarity. Responses expired by `now` are dropped along the way.
+    pub fn nearest(
+        &self,
+        scope: &Scope,
+        embedding: &[f32],
+        now: u64,
+    ) -> Option<(f32, CachedResponse)> {
+        let embedding = normalize(embedding.to_vec());
+        let mut scopes = self.scopes.lock().unwrap();
+        let indexed = scopes.get_mut(scope)?;
+        indexed.retain(|indexed| indexed.expires_at > now);
+
+        indexed
+            .iter()
+            .filter(|indexed| indexed.embedding.len() == embedding.len())
+            .map(|indexed| {
+                let similarity = indexed
+                    .embedding
+                    .iter()
+                    .zip(&embedding)
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>();
+                (similarity, indexed)
+            })
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(similarity, indexed)| (similarity, indexed.entry.clone()))
+    }
+
+    pub fn insert(
+        &self,
+        scope: Scope,
+        embedding: Vec<f32>,
+        entry: CachedResponse,
+        expires_at: u64,
+    ) {
+        let mut scopes = self.scopes.lock().unwrap();
+        let indexed = scopes.entry(scope).or_default();
+        if indexed.len() >= self.max_entries {
+            indexed.pop_front();
+        }
+
+        indexed.push_back(Indexed {
+            embedding: normalize(embedding),
+            expires_at,
+            entry,
+        });
+    }
+}
+
+fn normalize(mut embedding: Vec<f32>) -> Vec<f32> {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+
+    embedding
+}
@@ -0,0 +1,65 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::usage_ledger::UsageRecord;
+
+use super::{CacheBackend, CachedResponse};
+
+/// Keeps responses in the `response_cache` table of a SQLite database. Expired responses are
+/// deleted as new ones are stored.
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS response_cache (
+                key TEXT PRIMARY KEY,
+                expires_at INTEGER NOT NULL,
+                entry TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS response_cache_expires_at
+                ON response_cache (expires_at);",
+        )?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl CacheBackend for SqliteBackend {
+    fn get(&self, key: &str, now: u64) -> anyhow::Result<Option<CachedResponse>> {
+        let entry = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT entry FROM response_cache WHERE key = ?1 AND expires_at > ?2",
+                params![key, now as i64],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        Ok(entry
+            .map(|entry| serde_json::from_str(&entry))
+            .transpose()?)
+    }
+
+    fn put(&self, key: &str, entry: &CachedResponse, expires_at: u64) -> anyhow::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "DELETE FROM response_cache WHERE expires_at <= ?1",
+            params![UsageRecord::now() as i64],
+        )?;
+        connection.execute(
+            "INSERT OR REPLACE INTO response_cache (key, expires_at, entry) VALUES (?1, ?2, ?3)",
+            params![key, expires_at as i64, serde_json::to_string(entry)?],
+        )?;
+
+        Ok(())
+    }
+}
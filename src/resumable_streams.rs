@@ -0,0 +1,307 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, StatusCode},
+};
+use futures::{stream::BoxStream, Stream, StreamExt};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use tokio::sync::watch;
+use tracing::Instrument;
+
+use crate::{
+    caller::Caller, error::ApiError, llm_delegate::ModelTarget, response_cache::CacheStatus,
+};
+
+#[derive(Deserialize)]
+pub struct ResumableStreamsConfig {
+    /// How long a stream may still be resumed once it ended, in seconds.
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Streams a key may have kept at once, whether still running or ended but not expired.
+    #[serde(default = "default_max_streams_per_key")]
+    pub max_streams_per_key: usize,
+    /// Bytes of event data kept for a stream, past which it is ended with an error.
+    #[serde(default = "default_max_buffered_bytes")]
+    pub max_buffered_bytes: usize,
+}
+
+fn default_ttl_secs() -> u64 {
+    300
+}
+
+fn default_max_streams_per_key() -> usize {
+    32
+}
+
+fn default_max_buffered_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+/// The resumable streams, if enabled, along with the `Last-Event-ID` of the request, sent by
+/// clients reconnecting to a stream.
+pub struct Resumption {
+    pub streams: Option<ResumableStreams>,
+    pub last_event_id: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Resumption
+where
+    S: Send + Sync,
+    Option<ResumableStreams>: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            streams: Option::<ResumableStreams>::from_ref(state),
+            last_event_id: parts
+                .headers
+                .get("last-event-id")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// The events of a stream so far, and when it ended, if it did.
+#[derive(Default)]
+struct Events {
+    data: Vec<String>,
+    ended_at: Option<Instant>,
+}
+
+struct Buffered {
+    key: String,
+    target: ModelTarget,
+    cache: Option<CacheStatus>,
+    events: watch::Sender<Events>,
+}
+
+impl Buffered {
+    fn expired(&self, ttl: Duration) -> bool {
+        self.events
+            .borrow()
+            .ended_at
+            .is_some_and(|ended_at| ended_at.elapsed() >= ttl)
+    }
+}
+
+type Streams = Arc<Mutex<HashMap<String, Arc<Buffered>>>>;
+
+/// A stream started, along with its id, reported to clients so that they can resume it.
+pub struct Started {
+    pub id: String,
+    pub events: BoxStream<'static, (String, String)>,
+}
+
+/// A stream picked up again, along with what its response headers reported.
+pub struct Resumed {
+    pub id: String,
+    pub target: ModelTarget,
+    pub cache: Option<CacheStatus>,
+    /// The ids and data of the events following the last one the client received.
+    pub events: BoxStream<'static, (String, String)>,
+}
+
+/// Streamed completions run to their end whether or not their client stays connected, their
+/// events being kept so that a client that lost its connection can resume where it left off.
+///
+/// Event ids are made of the id of their stream and their index in it. Expired streams are
+/// pruned in the background.
+#[derive(Clone)]
+pub struct ResumableStreams {
+    streams: Streams,
+    ttl: Duration,
+    max_streams_per_key: usize,
+    max_buffered_bytes: usize,
+}
+
+impl ResumableStreams {
+    pub fn new(config: ResumableStreamsConfig) -> Self {
+        let streams = Streams::default();
+        let ttl = Duration::from_secs(config.ttl_secs);
+        tokio::spawn(prune(Arc::downgrade(&streams), ttl));
+
+        Self {
+            streams,
+            ttl,
+            max_streams_per_key: config.max_streams_per_key,
+            max_buffered_bytes: config.max_buffered_bytes,
+        }
+    }
+
+    /// Drives the data of the events of a stream to its end in the background, returning
+    /// its id and the stream of the ids and data of its events. Turned down when the key of
+    /// the caller already has as many streams as it may.
+    pub fn start(
+        &self,
+        caller: &Caller,
+        target: &ModelTarget,
+        cache: Option<CacheStatus>,
+        data: impl Stream<Item = String> + Send + 'static,
+    ) -> Result<Started, ApiError> {
+        let id = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect::<String>();
+        let buffered = Arc::new(Buffered {
+            key: caller.key.clone(),
+            target: target.clone(),
+            cache,
+            events: watch::channel(Events::default()).0,
+        });
+
+        {
+            let mut streams = self.streams.lock().unwrap();
+            let kept = streams
+                .values()
+                .filter(|kept| kept.key == caller.key && !kept.expired(self.ttl))
+                .count();
+            if kept >= self.max_streams_per_key {
+                return Err(ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "invalid_request_error",
+                    format!(
+                        "No more than {} resumable streams may be kept at once for a key.",
+                        self.max_streams_per_key
+                    ),
+                )
+                .with_code("stream_limit"));
+            }
+            streams.insert(id.clone(), buffered.clone());
+        }
+
+        let max_buffered_bytes = self.max_buffered_bytes;
+        tokio::spawn(
+            {
+                let buffered = buffered.clone();
+                async move {
+                    let mut data = std::pin::pin!(data);
+                    let mut buffered_bytes = 0;
+                    while let Some(item) = data.next().await {
+                        buffered_bytes += item.len();
+                        if buffered_bytes > max_buffered_bytes {
+                            // The status line is long gone, so the limit is reported in the
+                            // stream, which is cut short.
+                            let error = ApiError::new(
+                                StatusCode::TOO_MANY_REQUESTS,
+                                "invalid_request_error",
+                                format!(
+                                    "No more than {max_buffered_bytes} bytes may be kept for a \
+                                     resumable stream."
+                                ),
+                            )
+                            .with_code("stream_limit");
+                            let item = serde_json::json!({ "error": error.body() }).to_string();
+                            buffered.events.send_modify(|events| events.data.push(item));
+                            break;
+                        }
+                        buffered.events.send_modify(|events| events.data.push(item));
+                    }
+                    buffered
+                        .events
+                        .send_modify(|events| events.ended_at = Some(Instant::now()));
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Started {
+            events: events(id.clone(), buffered, 0),
+            id,
+        })
+    }
+
+    /// The events following `last_event_id`, if it belongs to a stream started by the same
+    /// key that has not expired.
+    pub fn resume(&self, caller: &Caller, last_event_id: &str) -> Option<Resumed> {
+        let (id, _) = last_event_id.rsplit_once(':')?;
+        self.resume_stream(caller, id, Some(last_event_id))
+    }
+
+    /// The events of the stream `id` following `last_event_id`, or all of them without one,
+    /// if it was started by the same key and has not expired.
+    pub fn resume_stream(
+        &self,
+        caller: &Caller,
+        id: &str,
+        last_event_id: Option<&str>,
+    ) -> Option<Resumed> {
+        let next = match last_event_id {
+            Some(last_event_id) => {
+                let (stream, last) = last_event_id.rsplit_once(':')?;
+                if stream != id {
+                    return None;
+                }
+                last.parse::<usize>().ok()?.checked_add(1)?
+            }
+            None => 0,
+        };
+
+        let buffered = self.streams.lock().unwrap().get(id).cloned()?;
+        if buffered.key != caller.key || buffered.expired(self.ttl) {
+            return None;
+        }
+
+        Some(Resumed {
+            id: id.to_string(),
+            target: buffered.target.clone(),
+            cache: buffered.cache,
+            events: events(id.to_string(), buffered, next),
+        })
+    }
+}
+
+/// Drops the streams that expired every `ttl`, until the streams themselves are dropped.
+async fn prune(streams: Weak<Mutex<HashMap<String, Arc<Buffered>>>>, ttl: Duration) {
+    // Intervals may not be empty.
+    let mut interval = tokio::time::interval(ttl.max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        let Some(streams) = streams.upgrade() else {
+            break;
+        };
+        streams
+            .lock()
+            .unwrap()
+            .retain(|_, buffered| !buffered.expired(ttl));
+    }
+}
+
+/// The events of a stream from the one at `next`, waiting for those still to come.
+fn events(
+    id: String,
+    buffered: Arc<Buffered>,
+    mut next: usize,
+) -> BoxStream<'static, (String, String)> {
+    Box::pin(async_stream::stream! {
+        // The sender lives as long as `buffered`, which is held here.
+        let mut receiver = buffered.events.subscribe();
+        loop {
+            let (pending, ended) = {
+                let events = receiver.borrow_and_update();
+                let pending = events.data.get(next..).unwrap_or_default().to_vec();
+                (pending, events.ended_at.is_some())
+            };
+
+            for data in pending {
+                yield (format!("{id}:{next}"), data);
+                next += 1;
+            }
+
+            if ended || receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    })
+}
@@ -1,8 +1,10 @@
 // mod aws_secret_manager_provider;
 mod env;
+mod overlay;
 mod secret_manager_error;
 mod secret_manager_provider;
 
 // pub use aws_secret_manager_provider::*;
 pub use env::*;
+pub use overlay::*;
 pub use secret_manager_provider::*;
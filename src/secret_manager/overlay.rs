@@ -0,0 +1,42 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::async_trait;
+
+use super::{secret_manager_error::SecretManagerError, SecretManagerProvider};
+
+/// Serves some secrets from memory and defers every other lookup to the wrapped provider.
+///
+/// Used to build provider clients with caller-supplied credentials, whose secret is dropped
+/// together with the overlay, and with the credentials of provider pool members.
+pub struct Overlay {
+    inner: Arc<dyn SecretManagerProvider>,
+    secrets: HashMap<String, String>,
+}
+
+impl Overlay {
+    pub fn new(
+        inner: Arc<dyn SecretManagerProvider>,
+        secret_id: &'static str,
+        secret: String,
+    ) -> Arc<Self> {
+        Self::with_secrets(inner, HashMap::from([(secret_id.to_string(), secret)]))
+    }
+
+    pub fn with_secrets(
+        inner: Arc<dyn SecretManagerProvider>,
+        secrets: HashMap<String, String>,
+    ) -> Arc<Self> {
+        Arc::new(Self { inner, secrets })
+    }
+}
+
+#[async_trait]
+impl SecretManagerProvider for Overlay {
+    async fn secret(&self, secret_id: &str) -> Result<String, SecretManagerError> {
+        if let Some(secret) = self.secrets.get(secret_id) {
+            return Ok(secret.clone());
+        }
+
+        self.inner.secret(secret_id).await
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rusqlite::{params, Connection};
+use tokio::sync::mpsc;
+
+use crate::{
+    audit_log::{AuditConfig, Redaction, PRUNE_INTERVAL},
+    llm_delegate::ModelTarget,
+    metrics::Metrics,
+    usage_ledger::UsageRecord,
+};
+
+/// How many records may wait to be written. Further records are dropped, rather than holding
+/// on to the requests and responses of every mirrored call while the disk is slow.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A request mirrored to a shadow target, along with both responses.
+pub struct ShadowRecord {
+    /// Milliseconds since the Unix epoch at which the request was received.
+    pub timestamp: u64,
+    pub key: String,
+    /// The model or alias requested by the client.
+    pub model: String,
+    pub request: serde_json::Value,
+    /// The target that served the client, unless the request failed.
+    pub primary: Option<ModelTarget>,
+    /// The response returned to the client. Missing for streamed responses.
+    pub primary_response: Option<serde_json::Value>,
+    pub shadow: ModelTarget,
+    pub shadow_response: Option<serde_json::Value>,
+    pub shadow_error: Option<String>,
+    pub shadow_latency: Duration,
+}
+
+/// Local store of shadow traffic, backed by SQLite, for comparing models offline.
+///
+/// Like the usage ledger, records are written by a background task. As they hold the same
+/// prompts and responses, they are redacted and deleted as the audit log's, when configured.
+#[derive(Clone)]
+pub struct ShadowStore {
+    sender: mpsc::Sender<ShadowRecord>,
+    metrics: Metrics,
+}
+
+impl ShadowStore {
+    pub fn open(
+        path: impl AsRef<Path>,
+        audit: Option<&AuditConfig>,
+        metrics: Metrics,
+    ) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS shadow (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                key TEXT NOT NULL,
+                model TEXT NOT NULL,
+                request TEXT NOT NULL,
+                primary_target TEXT,
+                primary_response TEXT,
+                shadow_target TEXT NOT NULL,
+                shadow_response TEXT,
+                shadow_error TEXT,
+                shadow_latency_ms INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS shadow_timestamp ON shadow (timestamp);",
+        )?;
+
+        let connection = Arc::new(Mutex::new(connection));
+        let redaction = Arc::new(Redaction::new(
+            audit
+                .map(|audit| audit.redact.as_slice())
+                .unwrap_or_default(),
+        ));
+        let retention = audit.and_then(AuditConfig::retention);
+        let (sender, mut receiver) = mpsc::channel::<ShadowRecord>(QUEUE_CAPACITY);
+
+        tokio::spawn(async move {
+            let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                tokio::select! {
+                    record = receiver.recv() => {
+                        let Some(mut record) = record else {
+                            break;
+                        };
+
+                        let connection = connection.clone();
+                        let redaction = redaction.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            redact(&redaction, &mut record);
+                            insert(&connection.lock().unwrap(), &record)
+                        })
+                        .await;
+
+                        match result {
+                            Ok(Err(e)) => tracing::error!("failed to write shadow record: {e}"),
+                            Err(e) => tracing::error!("shadow writer task failed: {e}"),
+                            Ok(Ok(())) => {}
+                        }
+                    }
+                    _ = prune.tick(), if retention.is_some() => {
+                        let cutoff = UsageRecord::now()
+                            .saturating_sub(retention.unwrap_or_default().as_millis() as u64);
+                        let connection = connection.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            connection.lock().unwrap().execute(
+                                "DELETE FROM shadow WHERE timestamp < ?1",
+                                params![cutoff as i64],
+                            )
+                        })
+                        .await;
+
+                        match result {
+                            Ok(Err(e)) => tracing::error!("failed to prune shadow records: {e}"),
+                            Err(e) => tracing::error!("shadow pruning task failed: {e}"),
+                            Ok(Ok(_)) => {}
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self { sender, metrics })
+    }
+
+    pub fn record(&self, record: ShadowRecord) {
+        match self.sender.try_send(record) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.metrics.count_dropped_shadow_record();
+                tracing::warn!("shadow store queue is full, dropping record");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::error!("shadow store writer has stopped, dropping record");
+            }
+        }
+    }
+}
+
+/// Redacts the request and both responses of a record as those of an audit record, each
+/// response standing for its `response`.
+fn redact(redaction: &Redaction, record: &mut ShadowRecord) {
+    let mut audited = serde_json::json!({
+        "request": record.request.take(),
+        "response": record.primary_response.take(),
+    });
+    redaction.apply(&mut audited);
+    record.request = audited["request"].take();
+    record.primary_response = Some(audited["response"].take()).filter(|r| !r.is_null());
+
+    let mut audited = serde_json::json!({ "response": record.shadow_response.take() });
+    redaction.apply(&mut audited);
+    record.shadow_response = Some(audited["response"].take()).filter(|r| !r.is_null());
+}
+
+fn insert(connection: &Connection, record: &ShadowRecord) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO shadow (
+            timestamp, key, model, request, primary_target, primary_response, shadow_target,
+            shadow_response, shadow_error, shadow_latency_ms
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            record.timestamp as i64,
+            record.key,
+            record.model,
+            record.request.to_string(),
+            record.primary.as_ref().map(ToString::to_string),
+            record.primary_response.as_ref().map(ToString::to_string),
+            record.shadow.to_string(),
+            record.shadow_response.as_ref().map(ToString::to_string),
+            record.shadow_error,
+            record.shadow_latency.as_millis() as i64,
+        ],
+    )?;
+
+    Ok(())
+}
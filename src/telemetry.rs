@@ -0,0 +1,368 @@
+use std::future::Future;
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderName, HeaderValue, Request},
+};
+use clap::ValueEnum;
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use tracing::{field::Empty, Level, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::Targets, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+use crate::{
+    entities::{CompletionUsage, FinishReason},
+    llm_delegate::{error_type, ModelTarget, SupportedLlm},
+};
+
+/// The header correlating a request across the logs of the gateway and of the providers.
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The headers in which the providers give the ID of a call, as asked for by their support:
+/// OpenAI and Vertex AI send `x-request-id`, Anthropic `request-id`.
+static UPSTREAM_REQUEST_ID_HEADERS: [HeaderName; 2] = [
+    HeaderName::from_static("x-request-id"),
+    HeaderName::from_static("request-id"),
+];
+
+tokio::task_local! {
+    /// The ID of the request an upstream call is made for.
+    static UPSTREAM_REQUEST_ID: Option<String>;
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the spans it was logged in.
+    Json,
+}
+
+/// Installs the logger and, when an OTLP endpoint is given, the export of spans to it.
+///
+/// The returned provider must be shut down before exiting, to flush the spans not yet exported.
+pub fn init(
+    log_format: LogFormat,
+    otlp_endpoint: Option<&str>,
+) -> anyhow::Result<Option<TracerProvider>> {
+    let provider = otlp_endpoint.map(tracer_provider).transpose()?;
+
+    if provider.is_some() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+    }
+
+    let otel = provider.as_ref().map(otel_layer);
+
+    let fmt = match log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(
+            fmt.with_filter(
+                EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| "llm_gateway=debug,tower_http=debug".into()),
+            ),
+        )
+        .with(otel)
+        .init();
+
+    Ok(provider)
+}
+
+fn tracer_provider(endpoint: &str) -> anyhow::Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            "llm-gateway",
+        )]))
+        .build())
+}
+
+/// Exports the spans of the gateway, whatever the log level.
+fn otel_layer<S>(provider: &TracerProvider) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("llm-gateway"))
+        .with_filter(Targets::new().with_target("llm_gateway", Level::INFO))
+}
+
+/// The span of a request to the gateway, continuing the trace of the client when it sent a
+/// `traceparent` header. Its request ID is attached to every log line within.
+pub fn request_span(request: &Request<Body>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        request_id = request
+            .headers()
+            .get(&REQUEST_ID)
+            .and_then(|value| value.to_str().ok()),
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        otel.name = format!("{} {}", request.method(), request.uri().path()),
+        otel.kind = "server",
+    );
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+
+    span
+}
+
+/// The span of a call to a provider, with the attributes of the GenAI semantic conventions.
+/// `operation` is either `chat` or `embeddings`. The attributes known from the response only
+/// are recorded with [`record_response`] or [`record_embeddings`].
+pub fn upstream_span(operation: &'static str, target: &ModelTarget) -> Span {
+    tracing::info_span!(
+        "upstream",
+        otel.name = format!("{operation} {}", target.model),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        gen_ai.operation.name = operation,
+        gen_ai.system = gen_ai_system(target.llm),
+        gen_ai.request.model = target.model,
+        gen_ai.response.id = Empty,
+        gen_ai.response.model = Empty,
+        gen_ai.response.finish_reasons = Empty,
+        gen_ai.usage.input_tokens = Empty,
+        gen_ai.usage.output_tokens = Empty,
+        error.type = Empty,
+        upstream.request_id = Empty,
+    )
+}
+
+/// Records the request ID a provider gave a call on the span of the call, which is the current
+/// one while its response is received. It identifies the call to the support of the provider.
+pub fn record_upstream_request_id(headers: &HeaderMap) {
+    let request_id = UPSTREAM_REQUEST_ID_HEADERS
+        .iter()
+        .find_map(|name| headers.get(name)?.to_str().ok());
+    if let Some(request_id) = request_id {
+        Span::current().record("upstream.request_id", request_id);
+    }
+}
+
+/// Records the response of a call on its span.
+pub fn record_response(
+    span: &Span,
+    id: &str,
+    model: &str,
+    usage: Option<&CompletionUsage>,
+    finish_reasons: &[FinishReason],
+) {
+    tracing::info!(parent: span, "response received");
+    span.record("gen_ai.response.id", id);
+    span.record("gen_ai.response.model", model);
+    if let Some(usage) = usage {
+        // Recorded as i64, as the unsigned integers are exported as strings.
+        span.record("gen_ai.usage.input_tokens", i64::from(usage.prompt_tokens));
+        span.record(
+            "gen_ai.usage.output_tokens",
+            i64::from(usage.completion_tokens),
+        );
+    }
+    if !finish_reasons.is_empty() {
+        let finish_reasons = finish_reasons
+            .iter()
+            .map(FinishReason::as_str)
+            .collect::<Vec<_>>();
+        span.record(
+            "gen_ai.response.finish_reasons",
+            tracing::field::debug(finish_reasons),
+        );
+    }
+}
+
+pub fn record_embeddings(span: &Span, model: &str, input_tokens: u32) {
+    span.record("gen_ai.response.model", model);
+    span.record("gen_ai.usage.input_tokens", i64::from(input_tokens));
+}
+
+/// Marks the span of a call as failed. Only the class of the error is recorded on the span; the
+/// error itself is logged within it.
+pub fn record_error(span: &Span, error: &anyhow::Error) {
+    tracing::warn!(parent: span, "upstream call failed: {error}");
+    span.record("otel.status_code", "ERROR");
+    span.record("error.type", error_type(error).as_ref());
+}
+
+/// Runs an upstream call on behalf of the request with the given ID.
+pub async fn with_request_id<F: Future>(request_id: Option<String>, call: F) -> F::Output {
+    UPSTREAM_REQUEST_ID.scope(request_id, call).await
+}
+
+/// Adds the `traceparent` of the current span, and the ID of the request the call is made for,
+/// to the headers of an upstream request.
+pub fn inject_headers(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+
+    let request_id = UPSTREAM_REQUEST_ID
+        .try_with(|request_id| request_id.clone())
+        .ok()
+        .flatten();
+    if let Some(request_id) = request_id.and_then(|id| HeaderValue::from_str(&id).ok()) {
+        headers.insert(REQUEST_ID.clone(), request_id);
+    }
+}
+
+fn gen_ai_system(llm: SupportedLlm) -> &'static str {
+    match llm {
+        SupportedLlm::OpenAi => "openai",
+        SupportedLlm::Anthropic => "anthropic",
+        SupportedLlm::AnthropicVertexAi => "vertex_ai",
+        SupportedLlm::PerplexityAi => "perplexity",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Bytes, routing::post, Router};
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::ExportTraceServiceRequest,
+        common::v1::{any_value::Value, KeyValue},
+        trace::v1::Span,
+    };
+    use prost::Message;
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    fn attribute<'a>(span: &'a Span, key: &str) -> Option<&'a Value> {
+        span.attributes
+            .iter()
+            .find(|attribute: &&KeyValue| attribute.key == key)
+            .and_then(|attribute| attribute.value.as_ref()?.value.as_ref())
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_upstream_spans_in_the_trace_of_the_client() {
+        let (sender, mut exports) = mpsc::unbounded_channel();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| async move {
+                let _ = sender.send(body);
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        let provider = tracer_provider(&endpoint).unwrap();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let subscriber = tracing_subscriber::registry().with(otel_layer(&provider));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let request = Request::post("/v1/chat/completions")
+                .header("traceparent", format!("00-{TRACE_ID}-{PARENT_ID}-01"))
+                .body(Body::empty())
+                .unwrap();
+            let _request = request_span(&request).entered();
+
+            let target = ModelTarget {
+                llm: SupportedLlm::OpenAi,
+                model: "gpt-4o".to_string(),
+            };
+            let span = upstream_span("chat", &target);
+            let usage = CompletionUsage {
+                prompt_tokens: 12,
+                completion_tokens: 5,
+                total_tokens: 17,
+                cache_creation_input_tokens: None,
+                cache_read_input_tokens: None,
+            };
+            record_response(
+                &span,
+                "chatcmpl-1",
+                "gpt-4o-2024-08-06",
+                Some(&usage),
+                &[FinishReason::Stop],
+            );
+        });
+
+        let flushed = provider.clone();
+        tokio::task::spawn_blocking(move || flushed.force_flush())
+            .await
+            .unwrap();
+        let body = exports.recv().await.unwrap();
+        let export = ExportTraceServiceRequest::decode(body).unwrap();
+        let spans = export
+            .resource_spans
+            .iter()
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .collect::<Vec<_>>();
+
+        let request = spans
+            .iter()
+            .find(|span| span.name == "POST /v1/chat/completions")
+            .unwrap();
+        assert_eq!(hex(&request.trace_id), TRACE_ID);
+        assert_eq!(hex(&request.parent_span_id), PARENT_ID);
+
+        let chat = spans
+            .iter()
+            .find(|span| span.name == "chat gpt-4o")
+            .unwrap();
+        assert_eq!(hex(&chat.trace_id), TRACE_ID);
+        assert_eq!(chat.parent_span_id, request.span_id);
+        assert_eq!(
+            attribute(chat, "gen_ai.operation.name"),
+            Some(&Value::StringValue("chat".to_string()))
+        );
+        assert_eq!(
+            attribute(chat, "gen_ai.system"),
+            Some(&Value::StringValue("openai".to_string()))
+        );
+        assert_eq!(
+            attribute(chat, "gen_ai.request.model"),
+            Some(&Value::StringValue("gpt-4o".to_string()))
+        );
+        assert_eq!(
+            attribute(chat, "gen_ai.response.model"),
+            Some(&Value::StringValue("gpt-4o-2024-08-06".to_string()))
+        );
+        assert_eq!(
+            attribute(chat, "gen_ai.usage.input_tokens"),
+            Some(&Value::IntValue(12))
+        );
+        assert_eq!(
+            attribute(chat, "gen_ai.usage.output_tokens"),
+            Some(&Value::IntValue(5))
+        );
+
+        let _ = provider.shutdown();
+    }
+}
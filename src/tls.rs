@@ -0,0 +1,183 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, service::service_fn, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use rustls::{
+    crypto::ring::default_provider, pki_types::CertificateDer, server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again after a failed accept, e.g. when out of file
+/// descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The verified certificate presented by a client over mutual TLS.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    /// The certificate subject, e.g. `CN=team-a, O=Example`.
+    pub subject: String,
+}
+
+#[derive(Clone)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle client certificates are verified against. Client certificates are required
+    /// when set.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsSettings {
+    fn load(&self) -> anyhow::Result<Arc<ServerConfig>> {
+        let provider = Arc::new(default_provider());
+
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("reading certificates from {}", self.cert.display()))?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&self.key)?))?
+            .with_context(|| format!("no private key found in {}", self.key.display()))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(rustls::DEFAULT_VERSIONS)?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(client_ca)?)) {
+                    roots.add(cert?)?;
+                }
+
+                builder.with_client_cert_verifier(
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?,
+                )
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Arc::new(config))
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Serves `app` over TLS, reloading the certificates whenever their files change.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    settings: TlsSettings,
+) -> anyhow::Result<()> {
+    let config = Arc::new(RwLock::new(settings.load()?));
+
+    tokio::spawn({
+        let config = config.clone();
+        async move {
+            let mut modified = settings.modified();
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+
+                let current = settings.modified();
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match settings.load() {
+                    Ok(reloaded) => {
+                        *config.write().unwrap() = reloaded;
+                        tracing::info!("reloaded TLS certificates");
+                    }
+                    Err(e) => tracing::error!("failed to reload TLS certificates: {e:#}"),
+                }
+            }
+        }
+    });
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("failed to accept connection: {e}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let acceptor = TlsAcceptor::from(config.read().unwrap().clone());
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        tracing::debug!("TLS handshake with {addr} failed: {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!("TLS handshake with {addr} timed out");
+                        return;
+                    }
+                };
+
+            let client_certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(client_certificate);
+
+            let service = service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(addr));
+                if let Some(client_certificate) = &client_certificate {
+                    request.extensions_mut().insert(client_certificate.clone());
+                }
+
+                app.clone().call(request)
+            });
+
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("connection with {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+fn client_certificate(cert: &CertificateDer<'_>) -> Option<ClientCertificate> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+
+    Some(ClientCertificate {
+        subject: cert.subject().to_string(),
+    })
+}
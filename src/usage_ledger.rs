@@ -0,0 +1,260 @@
+mod pricing;
+mod usage_query;
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection};
+use tokio::sync::mpsc;
+
+use crate::{
+    llm_delegate::{SupportedLlm, SHADOW_CALLER},
+    metrics::Metrics,
+};
+
+pub use pricing::known_cost;
+pub use usage_query::{UsageQuery, UsageRow};
+
+/// How many records may wait to be written. Further records are dropped, rather than piling up
+/// in memory while the disk is slow.
+const QUEUE_CAPACITY: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UsageStatus {
+    Success,
+    Error,
+    /// Abandoned before it completed, e.g. a hedged call that lost the race.
+    Cancelled,
+}
+
+impl UsageStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Error => "error",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// A single upstream call, as recorded for chargeback.
+#[derive(Clone)]
+pub struct UsageRecord {
+    /// Milliseconds since the Unix epoch at which the call started.
+    pub timestamp: u64,
+    pub key: String,
+    pub llm: SupportedLlm,
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub latency: Duration,
+    pub status: UsageStatus,
+    /// Whether the token counts were estimated by the gateway, the provider not reporting them.
+    pub estimated: bool,
+}
+
+impl UsageRecord {
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64
+    }
+}
+
+/// A call in progress, recorded once dropped with the tokens and status set on it by then.
+///
+/// Calls dropped before their status is set are recorded as cancelled, with the tokens counted
+/// so far.
+pub struct PendingUsage {
+    usage_ledger: UsageLedger,
+    started: Instant,
+    record: UsageRecord,
+}
+
+impl PendingUsage {
+    /// Sets the token counts reported by the provider.
+    pub fn set_tokens(&mut self, prompt_tokens: u32, completion_tokens: u32) {
+        self.record.prompt_tokens = prompt_tokens;
+        self.record.completion_tokens = completion_tokens;
+        self.record.estimated = false;
+    }
+
+    /// Sets token counts estimated by the gateway, until the provider reports the usage.
+    pub fn estimate_tokens(&mut self, prompt_tokens: u32, completion_tokens: u32) {
+        self.set_tokens(prompt_tokens, completion_tokens);
+        self.record.estimated = true;
+    }
+
+    /// Counts a streamed chunk as one completion token, until the provider reports the usage.
+    pub fn count_chunk(&mut self) {
+        self.record.completion_tokens += 1;
+        self.record.estimated = true;
+    }
+
+    pub fn fail(&mut self) {
+        self.record.status = UsageStatus::Error;
+    }
+
+    /// Marks the call as successful, unless it already failed.
+    pub fn succeed(&mut self) {
+        if self.record.status != UsageStatus::Error {
+            self.record.status = UsageStatus::Success;
+        }
+    }
+}
+
+impl Drop for PendingUsage {
+    fn drop(&mut self) {
+        self.record.latency = self.started.elapsed();
+        if self.record.status == UsageStatus::Cancelled {
+            tracing::info!(
+                "call to {}/{} cancelled after {:?}, with about {} prompt and {} completion tokens",
+                self.record.llm.to_string(),
+                self.record.model,
+                self.record.latency,
+                self.record.prompt_tokens,
+                self.record.completion_tokens,
+            );
+        }
+
+        self.usage_ledger.record(self.record.clone());
+    }
+}
+
+/// Durable ledger of every upstream call, backed by SQLite.
+///
+/// Records are handed to a background task so that the completion paths never wait on disk.
+/// They are counted in the metrics as they come in, even if the queue is full and they are not
+/// written.
+#[derive(Clone)]
+pub struct UsageLedger {
+    connection: Arc<Mutex<Connection>>,
+    sender: mpsc::Sender<UsageRecord>,
+    metrics: Metrics,
+}
+
+impl UsageLedger {
+    pub fn open(path: impl AsRef<Path>, metrics: Metrics) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                key TEXT NOT NULL,
+                provider TEXT NOT NULL,
+                model TEXT NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                latency_ms INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                cost REAL NOT NULL,
+                estimated INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS usage_timestamp ON usage (timestamp);",
+        )?;
+
+        // Ledgers created before token counts could be estimates lack the column.
+        let has_estimated = connection
+            .prepare("SELECT 1 FROM pragma_table_info('usage') WHERE name = 'estimated'")?
+            .exists([])?;
+        if !has_estimated {
+            connection.execute_batch(
+                "ALTER TABLE usage ADD COLUMN estimated INTEGER NOT NULL DEFAULT 0;",
+            )?;
+        }
+
+        let connection = Arc::new(Mutex::new(connection));
+        let (sender, mut receiver) = mpsc::channel::<UsageRecord>(QUEUE_CAPACITY);
+
+        let writer = connection.clone();
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let writer = writer.clone();
+                let result =
+                    tokio::task::spawn_blocking(move || insert(&writer.lock().unwrap(), &record))
+                        .await;
+
+                match result {
+                    Ok(Err(e)) => tracing::error!("failed to write usage record: {e}"),
+                    Err(e) => tracing::error!("usage writer task failed: {e}"),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+
+        Ok(Self {
+            connection,
+            sender,
+            metrics,
+        })
+    }
+
+    /// Writes a finished call to the ledger. Shadow calls are kept out of the metrics, which
+    /// describe the traffic clients rely on.
+    pub fn record(&self, record: UsageRecord) {
+        if record.key != SHADOW_CALLER {
+            self.metrics.observe_call(&record);
+        }
+        match self.sender.try_send(record) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.metrics.count_dropped_usage_record();
+                tracing::warn!("usage ledger queue is full, dropping record");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::error!("usage ledger writer has stopped, dropping record");
+            }
+        }
+    }
+
+    /// Starts recording a call, written to the ledger when the returned value is dropped.
+    pub fn start(&self, key: &str, llm: SupportedLlm, model: &str) -> PendingUsage {
+        PendingUsage {
+            usage_ledger: self.clone(),
+            started: Instant::now(),
+            record: UsageRecord {
+                timestamp: UsageRecord::now(),
+                key: key.to_string(),
+                llm,
+                model: model.to_string(),
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                latency: Duration::ZERO,
+                status: UsageStatus::Cancelled,
+                estimated: false,
+            },
+        }
+    }
+
+    pub async fn query(&self, query: UsageQuery) -> anyhow::Result<Vec<UsageRow>> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || query.run(&connection.lock().unwrap())).await?
+    }
+}
+
+fn insert(connection: &Connection, record: &UsageRecord) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO usage (
+            timestamp, key, provider, model, prompt_tokens, completion_tokens, latency_ms, status, cost,
+            estimated
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            record.timestamp as i64,
+            record.key,
+            record.llm.to_string(),
+            record.model,
+            record.prompt_tokens,
+            record.completion_tokens,
+            record.latency.as_millis() as i64,
+            record.status.as_str(),
+            pricing::cost(&record.model, record.prompt_tokens, record.completion_tokens),
+            record.estimated,
+        ],
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+/// Price of a model in USD per million tokens.
+struct ModelPrice {
+    prompt: f64,
+    completion: f64,
+}
+
+/// Known model prices, matched against the model identifier by prefix. More specific prefixes
+/// must come before the shorter ones they share a stem with.
+const MODEL_PRICES: &[(&str, ModelPrice)] = &[
+    (
+        "gpt-4o-mini",
+        ModelPrice {
+            prompt: 0.15,
+            completion: 0.6,
+        },
+    ),
+    (
+        "gpt-4o",
+        ModelPrice {
+            prompt: 5.0,
+            completion: 15.0,
+        },
+    ),
+    (
+        "gpt-4-turbo",
+        ModelPrice {
+            prompt: 10.0,
+            completion: 30.0,
+        },
+    ),
+    (
+        "gpt-4",
+        ModelPrice {
+            prompt: 30.0,
+            completion: 60.0,
+        },
+    ),
+    (
+        "gpt-3.5-turbo",
+        ModelPrice {
+            prompt: 0.5,
+            completion: 1.5,
+        },
+    ),
+    (
+        "claude-3-5-sonnet",
+        ModelPrice {
+            prompt: 3.0,
+            completion: 15.0,
+        },
+    ),
+    (
+        "claude-3-opus",
+        ModelPrice {
+            prompt: 15.0,
+            completion: 75.0,
+        },
+    ),
+    (
+        "claude-3-sonnet",
+        ModelPrice {
+            prompt: 3.0,
+            completion: 15.0,
+        },
+    ),
+    (
+        "claude-3-haiku",
+        ModelPrice {
+            prompt: 0.25,
+            completion: 1.25,
+        },
+    ),
+    (
+        "llama-3.1-sonar-small",
+        ModelPrice {
+            prompt: 0.2,
+            completion: 0.2,
+        },
+    ),
+    (
+        "llama-3.1-sonar-large",
+        ModelPrice {
+            prompt: 1.0,
+            completion: 1.0,
+        },
+    ),
+    (
+        "llama-3.1-sonar-huge",
+        ModelPrice {
+            prompt: 5.0,
+            completion: 5.0,
+        },
+    ),
+];
+
+/// Computes the cost in USD of a call, or zero when the model has no known price.
+pub fn cost(model: &str, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+    known_cost(model, prompt_tokens, completion_tokens).unwrap_or_default()
+}
+
+/// Computes the cost in USD of a call, if the model has a known price.
+pub fn known_cost(model: &str, prompt_tokens: u32, completion_tokens: u32) -> Option<f64> {
+    MODEL_PRICES
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, price)| {
+            (prompt_tokens as f64 * price.prompt + completion_tokens as f64 * price.completion)
+                / 1_000_000.0
+        })
+}
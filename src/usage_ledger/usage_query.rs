@@ -0,0 +1,225 @@
+use rusqlite::{params_from_iter, Connection};
+use serde::{Deserialize, Deserializer};
+
+#[derive(Clone, Copy)]
+pub enum UsageGroup {
+    Key,
+    Model,
+    Day,
+}
+
+impl UsageGroup {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Key => "key",
+            Self::Model => "model",
+            Self::Day => "day",
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            Self::Key => "key",
+            Self::Model => "model",
+            Self::Day => "date(timestamp / 1000, 'unixepoch')",
+        }
+    }
+}
+
+impl TryFrom<&str> for UsageGroup {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "key" => Ok(Self::Key),
+            "model" => Ok(Self::Model),
+            "day" => Ok(Self::Day),
+            _ => Err(anyhow::anyhow!("Unsupported usage grouping `{value}`")),
+        }
+    }
+}
+
+/// Filters and grouping for an aggregated usage report.
+#[derive(Clone, Default, Deserialize)]
+pub struct UsageQuery {
+    /// Comma separated list of `key`, `model` and `day`.
+    #[serde(default, deserialize_with = "deserialize_groups")]
+    pub group_by: Vec<UsageGroup>,
+    /// Inclusive lower bound, as a `YYYY-MM-DD` UTC day.
+    pub from: Option<String>,
+    /// Inclusive upper bound, as a `YYYY-MM-DD` UTC day.
+    pub to: Option<String>,
+    /// Restricts the report to a single key.
+    pub key: Option<String>,
+}
+
+#[derive(Default, serde::Serialize)]
+pub struct UsageRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub day: Option<String>,
+    pub requests: u64,
+    pub errors: u64,
+    pub cancelled: u64,
+    /// Requests whose token counts were estimated by the gateway.
+    pub estimated: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+    pub avg_latency_ms: f64,
+}
+
+impl UsageQuery {
+    pub(super) fn run(&self, connection: &Connection) -> anyhow::Result<Vec<UsageRow>> {
+        let columns = self
+            .group_by
+            .iter()
+            .map(UsageGroup::column)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut sql = String::from("SELECT ");
+        if !columns.is_empty() {
+            sql.push_str(&columns);
+            sql.push_str(", ");
+        }
+        sql.push_str(
+            "COUNT(*), SUM(status = 'error'), SUM(status = 'cancelled'), SUM(estimated), \
+             SUM(prompt_tokens), SUM(completion_tokens), SUM(cost), AVG(latency_ms) FROM usage \
+             WHERE 1 = 1",
+        );
+
+        let mut params = Vec::new();
+        if let Some(from) = &self.from {
+            params.push(from.clone());
+            sql.push_str(&format!(
+                " AND date(timestamp / 1000, 'unixepoch') >= ?{}",
+                params.len()
+            ));
+        }
+        if let Some(to) = &self.to {
+            params.push(to.clone());
+            sql.push_str(&format!(
+                " AND date(timestamp / 1000, 'unixepoch') <= ?{}",
+                params.len()
+            ));
+        }
+        if let Some(key) = &self.key {
+            params.push(key.clone());
+            sql.push_str(&format!(" AND key = ?{}", params.len()));
+        }
+        if !columns.is_empty() {
+            sql.push_str(&format!(" GROUP BY {columns} ORDER BY {columns}"));
+        }
+
+        let mut statement = connection.prepare(&sql)?;
+        let rows = statement
+            .query_map(params_from_iter(params.iter()), |row| {
+                let mut usage = UsageRow::default();
+                for (i, group) in self.group_by.iter().enumerate() {
+                    let value = row.get::<_, String>(i)?;
+                    match group {
+                        UsageGroup::Key => usage.key = Some(value),
+                        UsageGroup::Model => usage.model = Some(value),
+                        UsageGroup::Day => usage.day = Some(value),
+                    }
+                }
+
+                let offset = self.group_by.len();
+                usage.requests = row.get::<_, i64>(offset)? as u64;
+                usage.errors = row.get::<_, Option<i64>>(offset + 1)?.unwrap_or(0) as u64;
+                usage.cancelled = row.get::<_, Option<i64>>(offset + 2)?.unwrap_or(0) as u64;
+                usage.estimated = row.get::<_, Option<i64>>(offset + 3)?.unwrap_or(0) as u64;
+                usage.prompt_tokens = row.get::<_, Option<i64>>(offset + 4)?.unwrap_or(0) as u64;
+                usage.completion_tokens =
+                    row.get::<_, Option<i64>>(offset + 5)?.unwrap_or(0) as u64;
+                usage.cost = row.get::<_, Option<f64>>(offset + 6)?.unwrap_or(0.0);
+                usage.avg_latency_ms = row.get::<_, Option<f64>>(offset + 7)?.unwrap_or(0.0);
+
+                Ok(usage)
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    /// Renders rows produced by this query as CSV, with one column per grouping followed by the
+    /// aggregated metrics.
+    pub fn csv(&self, rows: &[UsageRow]) -> String {
+        let mut header = self
+            .group_by
+            .iter()
+            .map(|group| group.name().to_string())
+            .collect::<Vec<_>>();
+        header.extend(
+            [
+                "requests",
+                "errors",
+                "cancelled",
+                "estimated",
+                "prompt_tokens",
+                "completion_tokens",
+                "cost",
+                "avg_latency_ms",
+            ]
+            .map(String::from),
+        );
+
+        let mut csv = header.join(",");
+        csv.push('\n');
+
+        for row in rows {
+            let mut fields = self
+                .group_by
+                .iter()
+                .map(|group| {
+                    let value = match group {
+                        UsageGroup::Key => &row.key,
+                        UsageGroup::Model => &row.model,
+                        UsageGroup::Day => &row.day,
+                    };
+                    escape_csv(value.as_deref().unwrap_or_default())
+                })
+                .collect::<Vec<_>>();
+            fields.extend([
+                row.requests.to_string(),
+                row.errors.to_string(),
+                row.cancelled.to_string(),
+                row.estimated.to_string(),
+                row.prompt_tokens.to_string(),
+                row.completion_tokens.to_string(),
+                format!("{:.6}", row.cost),
+                format!("{:.1}", row.avg_latency_ms),
+            ]);
+
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+
+        csv
+    }
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn deserialize_groups<'de, D>(deserializer: D) -> Result<Vec<UsageGroup>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+
+    raw.split(',')
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .map(|group| UsageGroup::try_from(group).map_err(serde::de::Error::custom))
+        .collect()
+}
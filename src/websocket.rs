@@ -0,0 +1,290 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::StatusCode,
+    response::Response,
+    Extension,
+};
+use axum_extra::TypedHeader;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc,
+    task::{AbortHandle, JoinSet},
+};
+
+use crate::{
+    caller::Caller,
+    entities::{CreateCompletionRequest, CreateCompletionStreamResponse},
+    error::ApiError,
+    llm_delegate::{LlmDelegate, RoutingConstraints, Served, SupportedLlm},
+    response_cache::CacheControl,
+};
+
+/// The subprotocol of the transport. Browsers, which cannot set headers on a WebSocket, may
+/// offer their key as a `bearer.<token>` subprotocol alongside it.
+pub const PROTOCOL: &str = "llm-gateway";
+
+/// Frames queued for a connection beyond which its completions wait for the client.
+const OUTGOING_CAPACITY: usize = 64;
+
+/// Completions which may run at once on a connection. Further requests are turned down until
+/// one of them ends.
+const MAX_RUNNING: usize = 16;
+
+/// A frame sent by the client.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    /// Starts a completion, always streamed, under an id of the client's choosing. The
+    /// provider defaults to the `x-llm-provider` header of the connection.
+    Request {
+        id: String,
+        #[serde(default)]
+        provider: Option<SupportedLlm>,
+        request: Box<CreateCompletionRequest>,
+    },
+    /// Cancels the completion running under `id`.
+    Cancel { id: String },
+}
+
+/// A frame sent to the client, about the completion running under `id`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame {
+    /// The completion is being served by `served_by`, from the cache if `cache` says so.
+    Start {
+        id: String,
+        served_by: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache: Option<&'static str>,
+    },
+    Chunk {
+        id: String,
+        chunk: CreateCompletionStreamResponse,
+    },
+    /// The completion ended normally. No more frames follow for `id`.
+    Done { id: String },
+    /// The completion was cancelled by the client. No more frames follow for `id`.
+    Cancelled { id: String },
+    /// The completion failed, or the frame was rejected, in which case `id` is missing when
+    /// it could not be read. No more frames follow for `id`.
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        error: serde_json::Value,
+    },
+}
+
+impl ServerFrame {
+    fn error(id: Option<String>, error: ApiError) -> Self {
+        Self::Error {
+            id,
+            error: error.body(),
+        }
+    }
+
+    fn invalid(id: Option<String>, message: impl Into<String>) -> Self {
+        Self::error(
+            id,
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message),
+        )
+    }
+}
+
+/// Serves streamed chat completions over a WebSocket, for clients behind proxies that buffer
+/// server-sent events. Several completions may run at once on a connection, told apart by the
+/// ids the client gives them.
+///
+/// Authentication, and the routing headers, are those of the upgrade request, and hold for
+/// the whole connection.
+pub async fn completions(
+    ws: WebSocketUpgrade,
+    State(llm_delegate): State<LlmDelegate>,
+    Extension(caller): Extension<Caller>,
+    llm: Option<TypedHeader<SupportedLlm>>,
+    constraints: RoutingConstraints,
+    cache_control: CacheControl,
+) -> Response {
+    let connection = Connection {
+        llm_delegate,
+        caller,
+        llm: llm.map(|TypedHeader(llm)| llm),
+        constraints,
+        cache_control,
+    };
+
+    ws.protocols([PROTOCOL])
+        .on_upgrade(move |socket| connection.serve(socket))
+}
+
+#[derive(Clone)]
+struct Connection {
+    llm_delegate: LlmDelegate,
+    caller: Caller,
+    llm: Option<SupportedLlm>,
+    constraints: RoutingConstraints,
+    cache_control: CacheControl,
+}
+
+impl Connection {
+    async fn serve(self, socket: WebSocket) {
+        let (mut sink, mut incoming) = socket.split();
+        let (sender, mut outgoing) = mpsc::channel::<ServerFrame>(OUTGOING_CAPACITY);
+
+        let writer = tokio::spawn(async move {
+            while let Some(frame) = outgoing.recv().await {
+                let text = serde_json::to_string(&frame).unwrap();
+                if sink.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut completions = JoinSet::new();
+        let mut running = HashMap::<String, AbortHandle>::new();
+        loop {
+            let message = tokio::select! {
+                message = incoming.next() => message,
+                Some(finished) = completions.join_next_with_id() => {
+                    let finished = match finished {
+                        Ok((completion, ())) => completion,
+                        Err(e) => e.id(),
+                    };
+                    running.retain(|_, completion| completion.id() != finished);
+                    continue;
+                }
+            };
+
+            let text = match message {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                // Pings are answered by axum itself.
+                Some(Ok(_)) => continue,
+            };
+
+            let reply = match serde_json::from_str::<ClientFrame>(&text) {
+                Ok(ClientFrame::Request { id, .. }) if running.len() >= MAX_RUNNING => {
+                    Some(ServerFrame::error(
+                        Some(id),
+                        ApiError::new(
+                            StatusCode::TOO_MANY_REQUESTS,
+                            "invalid_request_error",
+                            format!(
+                                "No more than {MAX_RUNNING} completions may run at once on a \
+                                 connection."
+                            ),
+                        )
+                        .with_code("too_many_completions"),
+                    ))
+                }
+                Ok(ClientFrame::Request {
+                    id,
+                    provider,
+                    request,
+                }) => match running.entry(id) {
+                    Entry::Occupied(entry) => Some(ServerFrame::invalid(
+                        Some(entry.key().clone()),
+                        "A completion is already running under this id.",
+                    )),
+                    Entry::Vacant(entry) => {
+                        let completion = completions.spawn(self.clone().complete(
+                            entry.key().clone(),
+                            provider,
+                            *request,
+                            sender.clone(),
+                        ));
+                        entry.insert(completion);
+                        None
+                    }
+                },
+                Ok(ClientFrame::Cancel { id }) => match running.remove(&id) {
+                    Some(completion) => {
+                        // Dropping the stream records the usage so far as cancelled.
+                        completion.abort();
+                        Some(ServerFrame::Cancelled { id })
+                    }
+                    None => Some(ServerFrame::invalid(
+                        Some(id),
+                        "No completion is running under this id.",
+                    )),
+                },
+                Err(e) => Some(ServerFrame::invalid(None, e.to_string())),
+            };
+
+            if let Some(reply) = reply {
+                if sender.send(reply).await.is_err() {
+                    break;
+                }
+            }
+        }
+
+        // The client is gone, and so is any interest in the completions still running.
+        completions.abort_all();
+        writer.abort();
+    }
+
+    async fn complete(
+        self,
+        id: String,
+        provider: Option<SupportedLlm>,
+        mut request: CreateCompletionRequest,
+        sender: mpsc::Sender<ServerFrame>,
+    ) {
+        request.stream = Some(true);
+
+        let served = self
+            .llm_delegate
+            .completion_stream(
+                &self.caller,
+                provider.or(self.llm),
+                &self.constraints,
+                self.cache_control,
+                request,
+            )
+            .await;
+        let Served {
+            target,
+            response: mut stream,
+            cache,
+        } = match served {
+            Ok(served) => served,
+            Err(e) => {
+                let _ = sender.send(ServerFrame::error(Some(id), e.into())).await;
+                return;
+            }
+        };
+
+        let start = ServerFrame::Start {
+            id: id.clone(),
+            served_by: target.to_string(),
+            cache: cache.map(|cache| cache.as_str()),
+        };
+        if sender.send(start).await.is_err() {
+            return;
+        }
+
+        while let Some(item) = stream.next().await {
+            let frame = match item {
+                Ok(chunk) => ServerFrame::Chunk {
+                    id: id.clone(),
+                    chunk,
+                },
+                Err(e) => {
+                    let _ = sender.send(ServerFrame::error(Some(id), e.into())).await;
+                    return;
+                }
+            };
+
+            if sender.send(frame).await.is_err() {
+                return;
+            }
+        }
+
+        let _ = sender.send(ServerFrame::Done { id }).await;
+    }
+}